@@ -4,6 +4,8 @@ gateway {
   id        = "mumbai-gateway"
   region      = "ap-south-1"
   listen_port = 8080
+  advertise_address = "127.0.0.1:8080"
+  max_hops = 1
   
   services = [
     {
@@ -33,8 +35,25 @@ gateway {
 
   transport {
     type = "nats"
+    cluster = "default"
+    codec {
+      format = "msgpack"
+      compress_above = 4096
+    }
+    backpressure {
+      capacity = 100
+      overflow = "coalesce_latest_per_gateway"
+    }
+    request_timeout = "5s"
     nats  {
       url = "nats://localhost:4222"
+      client_id = "mumbai-gateway"
+      reconnect_wait = "1s"
+      # retains the latest stats and heartbeats for replay on startup,
+      # needs a server with JetStream enabled
+      # jetstream {
+      #   max_age = "60s"
+      # }
     }
   }
   
@@ -53,4 +72,15 @@ gateway {
     retries  = 5
     interval = "2s"
   }
+
+  store {
+    type                  = "memory"
+    service_stats_max_age = "30s"
+    gateway_stats_max_age = "30s"
+    sweep_interval        = "5s"
+
+    redis {
+      url = "redis://127.0.0.1:6379/"
+    }
+  }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 orbit {
+  id = "orbit"
   listen_port = 9090
   max_connections = 1000
 
@@ -15,8 +16,25 @@ orbit {
 
   transport {
     type = "nats"
+    cluster = "default"
+    codec {
+      format = "msgpack"
+      compress_above = 4096
+    }
+    backpressure {
+      capacity = 100
+      overflow = "coalesce_latest_per_gateway"
+    }
+    request_timeout = "5s"
     nats  {
       url = "nats://localhost:4222"
+      client_id = "orbit"
+      reconnect_wait = "1s"
+      # retains the latest stats and heartbeats for replay on startup,
+      # needs a server with JetStream enabled
+      # jetstream {
+      #   max_age = "60s"
+      # }
     }
   }
   
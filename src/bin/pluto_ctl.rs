@@ -0,0 +1,76 @@
+use pluto::common::logger::init_logger;
+use pluto::orbit::admin::{Admin, Node};
+use pluto::orbit::config::read_orbit_config;
+use tracing::error;
+
+const USAGE: &str = "usage:
+  pluto-ctl ping orbit
+  pluto-ctl ping gateway <gateway id>
+  pluto-ctl query-store <gateway id> <service id>";
+
+enum Command {
+    PingOrbit,
+    PingGateway(String),
+    QueryStore {
+        gateway_id: String,
+        service_id: String,
+    },
+}
+
+fn parse(args: &[String]) -> Option<Command> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["ping", "orbit"] => Some(Command::PingOrbit),
+        ["ping", "gateway", gateway_id] => Some(Command::PingGateway(gateway_id.to_string())),
+        ["query-store", gateway_id, service_id] => Some(Command::QueryStore {
+            gateway_id: gateway_id.to_string(),
+            service_id: service_id.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+// talks to the cluster of the orbit config through its broker,
+// ORBIT_CONFIG_PATH picks it
+#[tokio::main]
+async fn main() {
+    init_logger();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(command) = parse(&args) else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    if let Err(e) = run(command).await {
+        error!("{:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(command: Command) -> anyhow::Result<()> {
+    let config =
+        read_orbit_config().map_err(|e| anyhow::anyhow!("Unable to read config: {}", e))?;
+    let admin = Admin::new(&config).await?;
+    match command {
+        Command::PingOrbit => ping(&admin, Node::Orbit).await,
+        Command::PingGateway(gateway_id) => ping(&admin, Node::Gateway(&gateway_id)).await,
+        Command::QueryStore {
+            gateway_id,
+            service_id,
+        } => {
+            let paths = admin.query_store(&gateway_id, &service_id).await?;
+            if paths.paths.is_empty() {
+                println!("{} has no path to {}", gateway_id, service_id);
+            }
+            for path in paths.paths {
+                println!("{} ({:?})", path.hops.join(" -> "), path.latency);
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn ping(admin: &Admin, node: Node<'_>) -> anyhow::Result<()> {
+    let rtt = admin.ping(node).await?;
+    println!("pong from {:?} in {:?}", node, rtt);
+    Ok(())
+}
@@ -20,7 +20,9 @@ fn main() {
 
             // run the run() method in blocking way
             if let Err(e) = runtime.block_on(gateway.run()) {
-                println!("Pluto Gateway exited with error: {}", e);
+                // exit non-zero so that a supervisor restarts and resubscribes us
+                error!("Pluto Gateway exited with error: {:#}", e);
+                std::process::exit(1);
             }
         }
         Err(e) => error!("Failed to create Pluto Gateway: {}", e),
@@ -2,7 +2,7 @@ use pluto::common::logger::init_logger;
 use pluto::orbit::config::read_orbit_config;
 use pluto::orbit::orbit::Orbit;
 use std::sync::Arc;
-use tracing::info;
+use tracing::{error, info};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -14,7 +14,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("starting orbit");
 
     if let Err(e) = Arc::new(orbit).run().await {
-        println!("Pluto Orbit exited with error: {}", e);
+        // exit non-zero so that a supervisor restarts and resubscribes us
+        error!("Pluto Orbit exited with error: {:#}", e);
+        std::process::exit(1);
     }
 
     Ok(())
@@ -8,6 +8,7 @@ pub enum Error {
     SubscriptionError(String),
     SerializationError(String),
     DeserializationError(String),
+    RequestError(String),
 }
 
 impl fmt::Display for Error {
@@ -18,6 +19,7 @@ impl fmt::Display for Error {
             Error::SubscriptionError(msg) => write!(f, "Subscription error: {}", msg),
             Error::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
             Error::DeserializationError(msg) => write!(f, "Deserialization error: {}", msg),
+            Error::RequestError(msg) => write!(f, "Request error: {}", msg),
         }
     }
 }
@@ -1,7 +1,9 @@
+use crate::common::utils::{handle_duration_string, handle_optional_duration_string};
+use crate::gateway::store::store::ServicePath;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, time::Duration};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
 pub enum ServiceStatus {
     Up,
     Down,
@@ -18,25 +20,164 @@ pub struct ServiceStat {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct GatewayLatencyStats {
     pub gateway_id: String,
+    pub address: String,
     pub stats: HashMap<String, ServiceStat>,
 }
 
 impl GatewayLatencyStats {
-    pub fn new(gateway_id: String) -> Self {
+    pub fn new(gateway_id: String, address: String) -> Self {
         GatewayLatencyStats {
             gateway_id,
+            address,
             stats: HashMap::new(),
         }
     }
 }
 
+// latencies measured from one gateway to each of its peer gateways
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayMeshStats {
+    pub gateway_id: String,
+    pub latencies: HashMap<String, Duration>,
+    // peers that could not be probed
+    #[serde(default)]
+    pub unreachable: Vec<String>,
+}
+
+impl GatewayMeshStats {
+    pub fn new(gateway_id: String) -> Self {
+        GatewayMeshStats {
+            gateway_id,
+            latencies: HashMap::new(),
+            unreachable: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayHeartbeat {
+    pub gateway_id: String,
+    pub region: String,
+    pub address: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum GatewayStatus {
+    Alive,
+    Dead,
+}
+
+// liveness change of a gateway as observed by orbit
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayStatusUpdate {
+    pub gateway_id: String,
+    pub status: GatewayStatus,
+}
+
+// a proxied request failed on the path through `failed_gateway`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayFailoverEvent {
+    pub gateway_id: String,
+    pub service_id: String,
+    pub failed_gateway: String,
+    pub reason: String,
+}
+
+// asks a gateway for the paths its store holds to a service
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StoreQuery {
+    pub service_id: String,
+}
+
+// the paths to the service as seen by `gateway_id`, best path first
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StorePaths {
+    pub gateway_id: String,
+    pub service_id: String,
+    pub paths: Vec<ServicePath>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct TransportConfig {
     #[serde(rename = "type")]
     pub transport_type: TransportType,
+    // namespaces every subject, orbit and gateways only talk within their
+    // own cluster
+    #[serde(default = "default_cluster")]
+    pub cluster: String,
+    #[serde(default)]
+    pub codec: CodecConfig,
+    #[serde(default)]
+    pub backpressure: BackpressureConfig,
+    // how long a request waits for its reply
+    #[serde(default = "default_request_timeout", with = "handle_duration_string")]
+    pub request_timeout: Duration,
     pub nats: Option<NatsConfig>,
     pub kafka: Option<KafkaConfig>,
     pub rabbitmq: Option<RabbitMQConfig>,
+    pub redis: Option<RedisConfig>,
+    pub quic: Option<QuicConfig>,
+}
+
+fn default_cluster() -> String {
+    "default".to_string()
+}
+
+fn default_request_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+// how messages are encoded on the wire, decoding follows whatever the
+// publisher picked so processes can be switched over one at a time
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CodecConfig {
+    #[serde(default)]
+    pub format: WireFormat,
+    // payloads larger than this many bytes are compressed with zstd
+    pub compress_above: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+// what is buffered for a subscriber and what gives once it falls behind,
+// rather than holding up the backend's reader
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackpressureConfig {
+    #[serde(default = "default_backpressure_capacity")]
+    pub capacity: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_backpressure_capacity(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+fn default_backpressure_capacity() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    // waits for the subscriber, which holds up the backend's reader
+    #[default]
+    Block,
+    DropOldest,
+    // keeps only the latest stats, heartbeat and status of every gateway,
+    // otherwise drops the oldest
+    CoalesceLatestPerGateway,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -46,26 +187,94 @@ pub enum TransportType {
     Redis,
     Kafka,
     RabbitMQ,
+    // in process, for tests and running orbit and gateways in one binary
+    Memory,
+    // brokerless, gateways connect to orbit directly
+    Quic,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct NatsConfig {
     pub url: String,
+    // only meaningful to NATS streaming, which isn't supported
     pub cluster_id: Option<String>,
+    // the connection name the server reports this process under
     pub client_id: Option<String>,
     pub max_reconnects: Option<i32>,
+    // wait before the first reconnect, doubled on every failed attempt
+    #[serde(default, with = "handle_optional_duration_string")]
     pub reconnect_wait: Option<Duration>,
+    // at most one of token, user and password, nkey or credentials_file
+    pub token: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // seed of the nkey
+    pub nkey: Option<String>,
+    // a .creds file holding the user JWT and nkey seed
+    pub credentials_file: Option<String>,
+    pub tls: Option<NatsTlsConfig>,
+    // keeps the latest stats, heartbeats and statuses of every gateway in a
+    // stream, so processes catch up on startup and after losing their
+    // connection. Everything else stays on core NATS
+    pub jetstream: Option<JetStreamConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JetStreamConfig {
+    // created if missing, defaults to pluto_{cluster}
+    pub stream: Option<String>,
+    // retained messages older than this are dropped rather than replayed
+    #[serde(default = "default_jetstream_max_age", with = "handle_duration_string")]
+    pub max_age: Duration,
+    pub replicas: Option<usize>,
+}
+
+fn default_jetstream_max_age() -> Duration {
+    Duration::from_secs(60)
+}
+
+// TLS is required of the server once this block is present
+#[derive(Debug, Clone, Deserialize)]
+pub struct NatsTlsConfig {
+    // root trusted to sign the server's certificate, in addition to the
+    // system roots
+    pub ca_file: Option<String>,
+    // client certificate and key, for servers verifying clients
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct KafkaConfig {
     pub brokers: Vec<String>,
     pub client_id: String,
+    // prefix of the consumer groups, each process subscribes in groups of
+    // its own so client_id has to be unique per process
     pub group_id: String,
     pub max_message_bytes: Option<usize>,
     pub session_timeout_ms: Option<i32>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisConfig {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuicConfig {
+    // set on orbit, which accepts the gateways and relays between them
+    pub listen_address: Option<String>,
+    // set on gateways, where orbit listens
+    pub orbit_address: Option<String>,
+    // name in orbit's certificate, defaults to the host of orbit_address
+    pub server_name: Option<String>,
+    // orbit's certificate chain and key
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    // root trusted to sign orbit's certificate, defaults to the system roots
+    pub ca_file: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct RabbitMQConfig {
     pub url: String,
@@ -73,5 +282,6 @@ pub struct RabbitMQConfig {
     pub queue: String,
     pub routing_key: String,
     pub prefetch_count: Option<u16>,
+    #[serde(default, with = "handle_optional_duration_string")]
     pub connection_timeout: Option<Duration>,
 }
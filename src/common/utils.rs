@@ -22,3 +22,34 @@ pub mod handle_duration_string {
         Ok(Duration::from_secs(secs))
     }
 }
+
+// the same as handle_duration_string for settings that may be left out,
+// use along with `#[serde(default)]`
+pub mod handle_optional_duration_string {
+    use serde::{self, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match duration {
+            Some(duration) => super::handle_duration_string::serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let Some(s) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        let secs = s
+            .trim_end_matches('s')
+            .parse()
+            .map_err(serde::de::Error::custom)?;
+        Ok(Some(Duration::from_secs(secs)))
+    }
+}
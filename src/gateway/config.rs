@@ -12,12 +12,26 @@ pub struct Gateway {
     pub id: String,
     pub region: String,
     pub listen_port: u16,
+    // address other gateways use to reach this gateway's proxy listener
+    pub advertise_address: Option<String>,
+    // how many gateways a request may be forwarded through, defaults to 1
+    pub max_hops: Option<usize>,
 
     pub services: Vec<ServiceConfig>,
     pub transport: TransportConfig,
     pub latency: LatencyConfig,
     pub heartbeat: HeartbeatConfig,
     pub failover: FailoverConfig,
+    #[serde(default)]
+    pub store: StoreConfig,
+}
+
+impl Gateway {
+    pub fn advertise_address(&self) -> String {
+        self.advertise_address
+            .clone()
+            .unwrap_or_else(|| format!("127.0.0.1:{}", self.listen_port))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -26,6 +40,20 @@ pub struct ServiceConfig {
     pub address: String,
     pub port: u16,
     pub health_check: HealthCheckConfig,
+    // Host header and TLS SNI sent upstream, defaults to `address`
+    pub host: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+}
+
+impl ServiceConfig {
+    pub fn upstream_addr(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+
+    pub fn upstream_host(&self) -> &str {
+        self.host.as_deref().unwrap_or(&self.address)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -69,6 +97,56 @@ pub struct FailoverConfig {
     pub interval: Duration,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    #[serde(rename = "type")]
+    pub store_type: StoreType,
+    pub redis: Option<RedisStoreConfig>,
+    // how long a gateway-to-service stat is trusted without a fresh report
+    #[serde(with = "handle_duration_string")]
+    pub service_stats_max_age: Duration,
+    // how long a gateway-to-gateway stat is trusted without a fresh probe
+    #[serde(with = "handle_duration_string")]
+    pub gateway_stats_max_age: Duration,
+    #[serde(with = "handle_duration_string")]
+    pub sweep_interval: Duration,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            store_type: StoreType::default(),
+            redis: None,
+            service_stats_max_age: Duration::from_secs(30),
+            gateway_stats_max_age: Duration::from_secs(30),
+            sweep_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreType {
+    // state private to this gateway process
+    #[default]
+    Memory,
+    // state shared by every gateway process using the same redis
+    Redis,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisStoreConfig {
+    pub url: String,
+    // namespaces the keys so several clusters can share a redis
+    #[serde(default = "default_redis_key_prefix")]
+    pub key_prefix: String,
+}
+
+fn default_redis_key_prefix() -> String {
+    "pluto".to_string()
+}
+
 pub fn read_gateway_config() -> Result<GatewayConfig, Box<dyn std::error::Error>> {
     let config_path =
         std::env::var("GATEWAY_CONFIG_PATH").unwrap_or_else(|_| "config-gateway.hcl".to_string());
@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, info, trace, warn};
+
+use super::config::FailoverConfig;
+use super::gateway::Gateway;
+use crate::common::types::GatewayFailoverEvent;
+use crate::transport::pubsub::Message;
+use crate::transport::topics::PubSubTopics;
+
+// Paths (gateway, service) that recently failed a proxied request. A failed
+// path is skipped for `interval` unless nothing else can serve the service.
+#[derive(Debug)]
+pub struct Failover {
+    retries: u32,
+    interval: Duration,
+    failed_paths: RwLock<HashMap<(String, String), Instant>>,
+    events: mpsc::UnboundedSender<GatewayFailoverEvent>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<GatewayFailoverEvent>>>,
+}
+
+impl Failover {
+    pub fn new(conf: &FailoverConfig) -> Self {
+        let (events, receiver) = mpsc::unbounded_channel();
+        Self {
+            retries: conf.retries,
+            interval: conf.interval,
+            failed_paths: RwLock::new(HashMap::new()),
+            events,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn is_failed(&self, gateway_id: &str, service_id: &str) -> bool {
+        self.failed_paths
+            .read()
+            .unwrap()
+            .get(&(gateway_id.to_string(), service_id.to_string()))
+            .is_some_and(|failed_at| failed_at.elapsed() < self.interval)
+    }
+
+    pub fn mark_failed(&self, gateway_id: &str, service_id: &str) {
+        debug!(
+            "marking path through gateway {} to service {} as failed",
+            gateway_id, service_id
+        );
+        let mut failed_paths = self.failed_paths.write().unwrap();
+        failed_paths.retain(|_, failed_at| failed_at.elapsed() < self.interval);
+        failed_paths.insert(
+            (gateway_id.to_string(), service_id.to_string()),
+            Instant::now(),
+        );
+    }
+
+    // marks the path as failed and queues the event for publishing
+    pub fn report(&self, event: GatewayFailoverEvent) {
+        self.mark_failed(&event.failed_gateway, &event.service_id);
+        if self.events.send(event).is_err() {
+            warn!("failover event publisher is gone, dropping event");
+        }
+    }
+
+    fn take_events(&self) -> Option<mpsc::UnboundedReceiver<GatewayFailoverEvent>> {
+        self.receiver.lock().unwrap().take()
+    }
+}
+
+impl Gateway {
+    pub(crate) fn spawn_failover_publisher(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning failover publisher");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_publishing_failovers().await })
+    }
+
+    pub(crate) fn spawn_failover_receiver(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning failover receiver");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_receiving_failovers().await })
+    }
+
+    async fn start_publishing_failovers(&self) -> Result<()> {
+        info!("starting publishing failovers");
+        let mut events = self
+            .failover
+            .take_events()
+            .context("Failover events are already being published")?;
+
+        while let Some(event) = events.recv().await {
+            warn!(
+                "failing over service {} from gateway {}: {}",
+                event.service_id, event.failed_gateway, event.reason
+            );
+            // the events can't be taken again, so this keeps publishing
+            // whatever the broker does. Peers only miss out on this event,
+            // it is applied locally already
+            if let Err(e) = self
+                .transport
+                .broadcast(
+                    &[self.publish_topic(PubSubTopics::PublishGatewayFailover)],
+                    Message::GatewayFailover(event),
+                )
+                .await
+            {
+                warn!("failed to broadcast failover event: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn start_receiving_failovers(&self) -> Result<()> {
+        info!("starting receiving failovers");
+        let mut rcv = self
+            .transport
+            .subscribe_to_topics(&self.subscribe_topics(PubSubTopics::SubscribeGatewayFailover))
+            .await
+            .context("Failed to subscribe to topics")?;
+
+        while let Some(envelope) = rcv.recv().await {
+            // orbit relays our own events back, they were applied when sent
+            if envelope.sender == *self.transport.sender() {
+                continue;
+            }
+            if let Message::GatewayFailover(event) = envelope.message {
+                trace!("received failover event: {:?}", event);
+                self.failover
+                    .mark_failed(&event.failed_gateway, &event.service_id);
+            }
+        }
+        bail!("subscription to failover events lost")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_path_expires() {
+        let failover = Failover::new(&FailoverConfig {
+            retries: 2,
+            interval: Duration::from_millis(50),
+        });
+        failover.report(GatewayFailoverEvent {
+            gateway_id: "gateway1".to_string(),
+            service_id: "service1".to_string(),
+            failed_gateway: "gateway2".to_string(),
+            reason: "connection refused".to_string(),
+        });
+
+        assert!(failover.is_failed("gateway2", "service1"));
+        assert!(!failover.is_failed("gateway2", "service2"));
+        assert!(!failover.is_failed("gateway1", "service1"));
+        assert_eq!(
+            failover
+                .take_events()
+                .unwrap()
+                .try_recv()
+                .unwrap()
+                .failed_gateway,
+            "gateway2"
+        );
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!failover.is_failed("gateway2", "service1"));
+    }
+}
@@ -1,31 +1,50 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::task::JoinHandle;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, trace, warn};
 
-use super::config::{GatewayConfig, ServiceConfig};
-use super::latency::get_service_latency;
+use super::config::{GatewayConfig, ServiceConfig, StoreType};
+use super::failover::Failover;
+use super::latency::{get_gateway_latency, get_service_latency};
+use super::peers::{Peer, Peers};
 use super::store::memory::InMemoryStore;
-use crate::common::types::{GatewayLatencyStats, TransportType};
+use super::store::redis::RedisStore;
+use crate::common::types::{GatewayLatencyStats, GatewayMeshStats};
 use crate::gateway::store::store::Store as StoreTrait;
-use crate::transport;
-use crate::transport::pubsub::{Message, PubSubManager};
-use crate::transport::topics::PubSubTopics;
+use crate::transport::factory::create_transport;
+use crate::transport::pubsub::{Envelope, Message, PubSub, PubSubManager, Sender};
+use crate::transport::topics::{PubSubTopics, Target, Topic};
 
 #[derive(Debug)]
 pub struct Gateway {
-    id: String,
-    gateway_config: GatewayConfig,
-    services: HashMap<String, ServiceConfig>,
-    transport: Arc<PubSubManager<transport::nats::NatsPubSub>>,
-    store: Arc<dyn StoreTrait>,
+    pub(crate) id: String,
+    pub(crate) gateway_config: GatewayConfig,
+    pub(crate) services: HashMap<String, ServiceConfig>,
+    pub(crate) transport: Arc<PubSubManager>,
+    pub(crate) store: Arc<dyn StoreTrait>,
+    pub(crate) peers: Arc<Peers>,
+    pub(crate) failover: Arc<Failover>,
 }
 
 impl Gateway {
     pub async fn new(conf: &GatewayConfig) -> Result<Self> {
-        let transport = Self::create_transport(conf).await?;
-        let manager = Arc::new(PubSubManager::new(transport));
+        let transport = create_transport(&conf.gateway.transport).await?;
+        Self::with_transport(conf, transport).await
+    }
+
+    // builds a gateway on an already connected transport, e.g. a memory
+    // transport shared with orbit and other gateways in the same process
+    pub async fn with_transport(conf: &GatewayConfig, transport: Arc<dyn PubSub>) -> Result<Self> {
+        let manager = Arc::new(
+            PubSubManager::new(
+                transport,
+                &conf.gateway.transport.cluster,
+                Sender::gateway(&conf.gateway.id),
+            )
+            .with_backpressure(conf.gateway.transport.backpressure.clone()),
+        );
 
         let services = conf
             .gateway
@@ -38,40 +57,84 @@ impl Gateway {
             id: conf.gateway.id.clone(),
             gateway_config: conf.clone(),
             transport: manager,
-            store: Arc::new(InMemoryStore::new()),
+            store: Self::create_store(conf).await?,
+            peers: Arc::new(Peers::new()),
+            failover: Arc::new(Failover::new(&conf.gateway.failover)),
             services,
         })
     }
 
-    async fn create_transport(conf: &GatewayConfig) -> Result<transport::nats::NatsPubSub> {
-        match conf.gateway.transport.transport_type {
-            TransportType::Nats => {
-                let nats_config = conf
-                    .gateway
-                    .transport
-                    .nats
-                    .clone()
-                    .context("NATS configuration missing")?;
-                transport::nats::NatsPubSub::new(nats_config).await
+    async fn create_store(conf: &GatewayConfig) -> Result<Arc<dyn StoreTrait>> {
+        let store_config = &conf.gateway.store;
+        match store_config.store_type {
+            StoreType::Memory => Ok(Arc::new(InMemoryStore::with_config(
+                conf.gateway.id.clone(),
+                store_config,
+            ))),
+            StoreType::Redis => {
+                let redis_config = store_config
+                    .redis
+                    .as_ref()
+                    .context("Redis store configuration missing")?;
+                let store =
+                    RedisStore::new(conf.gateway.id.clone(), redis_config, store_config).await?;
+                Ok(Arc::new(store))
             }
-            _ => anyhow::bail!("Invalid transport type"),
         }
     }
 
+    // subject this gateway publishes `topic` on
+    pub(crate) fn publish_topic(&self, topic: PubSubTopics) -> Topic {
+        topic.gateway_subject(&self.gateway_config.gateway.region, &self.id)
+    }
+
+    // patterns matching everything orbit sends this gateway on `topic`
+    pub(crate) fn subscribe_topics(&self, topic: PubSubTopics) -> Vec<Topic> {
+        topic.orbit_patterns(&self.gateway_config.gateway.region, &self.id)
+    }
+
     pub async fn run(self: Arc<Self>) -> Result<()> {
         info!("starting gateway");
         let stats_sender = self.spawn_stats_sender();
         let stats_receiver = self.spawn_stats_receiver();
+        let mesh_prober = self.spawn_mesh_prober();
+        let heartbeat_sender = self.spawn_heartbeat_sender();
+        let status_receiver = self.spawn_status_receiver();
+        let failover_publisher = self.spawn_failover_publisher();
+        let failover_receiver = self.spawn_failover_receiver();
+        let store_sweeper = self.spawn_store_sweeper();
+        let request_handler = self.spawn_request_handler();
 
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
                 error!("Received shutdown signal");
             }
             res = stats_sender => {
-                let _ = res.context("Stats sender task failed")?;
+                res.context("Stats sender task failed")??;
             }
             res = stats_receiver => {
-                let _ = res.context("Stats receiver task failed")?;
+                res.context("Stats receiver task failed")??;
+            }
+            res = mesh_prober => {
+                res.context("Mesh prober task failed")??;
+            }
+            res = heartbeat_sender => {
+                res.context("Heartbeat sender task failed")??;
+            }
+            res = status_receiver => {
+                res.context("Status receiver task failed")??;
+            }
+            res = failover_publisher => {
+                res.context("Failover publisher task failed")??;
+            }
+            res = failover_receiver => {
+                res.context("Failover receiver task failed")??;
+            }
+            res = store_sweeper => {
+                res.context("Store sweeper task failed")??;
+            }
+            res = request_handler => {
+                res.context("Request handler task failed")??;
             }
         }
 
@@ -91,6 +154,30 @@ impl Gateway {
         tokio::spawn(async move { self_clone.start_receiving_stats().await })
     }
 
+    fn spawn_mesh_prober(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning mesh prober");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_probing_gateways().await })
+    }
+
+    fn spawn_store_sweeper(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning store sweeper");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_sweeping_store().await })
+    }
+
+    // evicts stats of gateways and services that stopped reporting
+    async fn start_sweeping_store(&self) -> Result<()> {
+        info!("starting sweeping store");
+        let mut interval = tokio::time::interval(self.gateway_config.gateway.store.sweep_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.store.evict_expired().await {
+                warn!("failed to evict expired stats: {}", e);
+            }
+        }
+    }
+
     async fn start_sending_stats(&self) -> Result<()> {
         info!("starting sending stats");
         let mut interval = tokio::time::interval(self.gateway_config.gateway.latency.interval);
@@ -102,45 +189,476 @@ impl Gateway {
                 futures_util::future::join_all(self.services.values().map(get_service_latency))
                     .await;
 
-            let mut stats = GatewayLatencyStats::new(self.id.clone());
+            let mut stats = GatewayLatencyStats::new(
+                self.id.clone(),
+                self.gateway_config.gateway.advertise_address(),
+            );
 
             for stat in latencies {
                 stats.stats.insert(stat.service_id.clone(), stat);
             }
 
-            self.transport
+            // our own stats are ignored when orbit relays them, record them here
+            if let Err(e) = self
+                .store
+                .update_gateway_to_service_stats(stats.clone(), SystemTime::now())
+                .await
+            {
+                warn!("failed to record own latency stats: {}", e);
+            }
+
+            // the next interval sends fresh stats anyway
+            if let Err(e) = self
+                .transport
                 .broadcast(
-                    &[PubSubTopics::GatewayToOrbitStats],
+                    &[self.publish_topic(PubSubTopics::GatewayToOrbitStats)],
                     Message::GatewayLatencyStats(stats),
                 )
                 .await
-                .context("Failed to broadcast gateway latency stats")?;
+            {
+                warn!("failed to broadcast gateway latency stats: {}", e);
+            }
         }
     }
 
+    async fn start_probing_gateways(&self) -> Result<()> {
+        info!("starting probing gateways");
+        let latency_config = &self.gateway_config.gateway.latency;
+        let mut interval = tokio::time::interval(latency_config.interval);
+
+        loop {
+            interval.tick().await;
+
+            let peers = self.peers.list();
+            if peers.is_empty() {
+                trace!("no peer gateways discovered yet, skipping probe");
+                continue;
+            }
+
+            let stats = self.probe_peers(&peers).await;
+            if let Err(e) = self
+                .transport
+                .broadcast(
+                    &[self.publish_topic(PubSubTopics::GatewayToOrbitMeshStats)],
+                    Message::GatewayMeshStats(stats),
+                )
+                .await
+            {
+                warn!("failed to broadcast gateway mesh stats: {}", e);
+            }
+        }
+    }
+
+    // measures the latency to every peer and records it in the store,
+    // peers that couldn't be reached are removed from the mesh
+    async fn probe_peers(&self, peers: &[Peer]) -> GatewayMeshStats {
+        let latency_config = &self.gateway_config.gateway.latency;
+        let results = futures_util::future::join_all(peers.iter().map(|peer| async {
+            let res = get_gateway_latency(&peer.address, latency_config.timeout).await;
+            (peer.id.clone(), res)
+        }))
+        .await;
+
+        let mut stats = GatewayMeshStats::new(self.id.clone());
+        for (gateway_id, res) in results {
+            match res {
+                Ok(latency) => {
+                    if let Err(e) = self
+                        .store
+                        .update_gateway_to_gateway_stats(
+                            self.id.clone(),
+                            gateway_id.clone(),
+                            latency,
+                            SystemTime::now(),
+                        )
+                        .await
+                    {
+                        warn!("failed to record latency to gateway {}: {}", gateway_id, e);
+                    }
+                    stats.latencies.insert(gateway_id, latency);
+                }
+                Err(e) => {
+                    debug!("failed to probe gateway {}: {}", gateway_id, e);
+                    if let Err(e) = self
+                        .store
+                        .remove_gateway_to_gateway_stats(&self.id, &gateway_id, SystemTime::now())
+                        .await
+                    {
+                        warn!("failed to remove latency to gateway {}: {}", gateway_id, e);
+                    }
+                    stats.unreachable.push(gateway_id);
+                }
+            }
+        }
+        stats
+    }
+
     async fn start_receiving_stats(&self) -> Result<()> {
         info!("starting receiving stats");
+        let topics = [
+            self.subscribe_topics(PubSubTopics::OrbitToGatewayStats),
+            self.subscribe_topics(PubSubTopics::OrbitToGatewayMeshStats),
+        ]
+        .concat();
         let mut rcv = self
             .transport
-            .subscribe_to_topics(&[PubSubTopics::OrbitToGatewayStats])
+            .subscribe_to_topics(&topics)
             .await
             .context("Failed to subscribe to topics")?;
 
-        while let Some(msg) = rcv.recv().await {
-            if let Message::GatewayLatencyStats(stats) = msg {
-                self.handle_latency_stats(stats).await?;
+        // catch up on what every gateway last published, when the transport
+        // retains it. Subscribed first so nothing falls in between, the
+        // store discards whatever arrives out of order
+        let mut replayed = self
+            .transport
+            .replay(&[
+                PubSubTopics::GatewayToOrbitStats.gateway_pattern(Target::All),
+                PubSubTopics::GatewayToOrbitMeshStats.gateway_pattern(Target::All),
+            ])
+            .await
+            .context("Failed to replay stats")?;
+        while let Some(envelope) = replayed.recv().await {
+            self.catch_up(envelope).await?;
+        }
+
+        // orbit's snapshot arrives alongside fresh stats, rather than holding
+        // them up while orbit is unreachable
+        let receive = async {
+            while let Some(envelope) = rcv.recv().await {
+                self.handle_stats(envelope).await?;
             }
+            Err::<(), _>(anyhow!("subscription to stats lost"))
+        };
+        tokio::try_join!(self.catch_up_on_snapshot(), receive)?;
+        Ok(())
+    }
+
+    // the latest stats orbit relayed, before this gateway subscribed
+    async fn catch_up_on_snapshot(&self) -> Result<()> {
+        let reply = self
+            .transport
+            .request(
+                &Topic::orbit_requests(),
+                Message::SnapshotRequest,
+                self.gateway_config.gateway.transport.request_timeout,
+            )
+            .await;
+        match reply.map(|reply| reply.message) {
+            Ok(Message::Snapshot(envelopes)) => {
+                debug!("catching up on {} stats from orbit", envelopes.len());
+                for envelope in envelopes {
+                    self.catch_up(envelope).await?;
+                }
+            }
+            Ok(other) => warn!("expected a snapshot from orbit, got {:?}", other),
+            Err(e) => warn!("no snapshot from orbit, waiting for fresh stats: {:#}", e),
         }
         Ok(())
     }
 
-    async fn handle_latency_stats(&self, stats: GatewayLatencyStats) -> Result<()> {
-        if self.gateway_config.gateway.id == stats.gateway_id {
-            debug!("received latency stats from self, ignoring");
+    // handles stats published a while ago, unless the store would take
+    // them as fresh while they already expired
+    async fn catch_up(&self, envelope: Envelope) -> Result<()> {
+        let store = &self.gateway_config.gateway.store;
+        let max_age = match envelope.message {
+            Message::GatewayMeshStats(_) => store.gateway_stats_max_age,
+            _ => store.service_stats_max_age,
+        };
+        if envelope.sent_at.elapsed().unwrap_or_default() > max_age {
+            return Ok(());
+        }
+        self.handle_stats(envelope).await
+    }
+
+    async fn handle_stats(&self, envelope: Envelope) -> Result<()> {
+        if envelope.sender == *self.transport.sender() {
+            debug!("received stats from self, ignoring");
             return Ok(()); // ignore stats from self
         }
+        match envelope.message {
+            Message::GatewayLatencyStats(stats) => {
+                self.handle_latency_stats(stats, envelope.sent_at).await
+            }
+            Message::GatewayMeshStats(stats) => {
+                self.handle_mesh_stats(stats, envelope.sent_at).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn handle_latency_stats(
+        &self,
+        stats: GatewayLatencyStats,
+        reported_at: SystemTime,
+    ) -> Result<()> {
         info!("received latency stats: {:?}", stats);
-        self.store.update_gateway_to_service_stats(stats);
+        self.peers.upsert(&stats.gateway_id, &stats.address);
+        // the store is refreshed by the next round of stats, so a failed
+        // write isn't fatal
+        if let Err(e) = self
+            .store
+            .update_gateway_to_service_stats(stats, reported_at)
+            .await
+        {
+            warn!("failed to record latency stats: {}", e);
+        }
         Ok(())
     }
+
+    async fn handle_mesh_stats(
+        &self,
+        stats: GatewayMeshStats,
+        reported_at: SystemTime,
+    ) -> Result<()> {
+        debug!("received mesh stats: {:?}", stats);
+        for (to_gateway, latency) in stats.latencies {
+            if let Err(e) = self
+                .store
+                .update_gateway_to_gateway_stats(
+                    stats.gateway_id.clone(),
+                    to_gateway,
+                    latency,
+                    reported_at,
+                )
+                .await
+            {
+                warn!("failed to record mesh stats: {}", e);
+            }
+        }
+        for to_gateway in stats.unreachable {
+            if let Err(e) = self
+                .store
+                .remove_gateway_to_gateway_stats(&stats.gateway_id, &to_gateway, reported_at)
+                .await
+            {
+                warn!("failed to remove mesh stats: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::gateway::router::Route;
+    use crate::orbit::admin::{Admin, Node};
+    use crate::orbit::config::OrbitConfig;
+    use crate::orbit::orbit::Orbit;
+    use crate::transport::memory::{MemoryBroker, MemoryPubSub};
+
+    const ORBIT_CONFIG: &str = r#"
+        orbit {
+          listen_port     = 9090
+          max_connections = 10
+          transport { type = "memory" }
+          heartbeat {
+            interval = "1s"
+            timeout  = "1s"
+            retries  = 3
+          }
+          load_balancing { method = "round_robin" }
+          security {
+            ssl_enabled = false
+            cert_file   = ""
+            key_file    = ""
+          }
+          logging {
+            level = "debug"
+            file  = ""
+          }
+          metrics {
+            enabled  = false
+            endpoint = "/metrics"
+          }
+        }
+    "#;
+
+    // listeners stand in for the proxy and service ports, probes only connect
+    async fn listener() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    fn gateway_config(id: &str, proxy_port: u16, services: &str) -> GatewayConfig {
+        hcl::from_str(&format!(
+            r#"
+            gateway {{
+              id                = "{id}"
+              region            = "local"
+              listen_port       = {proxy_port}
+              advertise_address = "127.0.0.1:{proxy_port}"
+              services          = [{services}]
+              transport {{ type = "memory" }}
+              latency {{
+                interval = "1s"
+                timeout  = "1s"
+              }}
+              heartbeat {{
+                interval = "1s"
+                retries  = 3
+                timeout  = "1s"
+              }}
+              failover {{
+                retries  = 1
+                interval = "1s"
+              }}
+            }}
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mesh_probes_reach_peer_stores() {
+        let broker = MemoryBroker::new();
+        let transport = || -> Arc<dyn PubSub> { Arc::new(MemoryPubSub::new(Arc::clone(&broker))) };
+        let (_proxy2, proxy2_port) = listener().await;
+        // nothing listens on gateway3's port any more
+        let (proxy3, proxy3_port) = listener().await;
+        drop(proxy3);
+
+        let gateway1 = Gateway::with_transport(&gateway_config("gateway1", 0, ""), transport())
+            .await
+            .unwrap();
+        let gateway2 = Gateway::with_transport(&gateway_config("gateway2", 0, ""), transport())
+            .await
+            .unwrap();
+        gateway1
+            .peers
+            .upsert("gateway2", &format!("127.0.0.1:{}", proxy2_port));
+        gateway1
+            .peers
+            .upsert("gateway3", &format!("127.0.0.1:{}", proxy3_port));
+
+        // gateway3 was reachable before, gateway2 knew it as well
+        let reachable_before = SystemTime::now() - Duration::from_secs(1);
+        for store in [&gateway1.store, &gateway2.store] {
+            store
+                .update_gateway_to_gateway_stats(
+                    "gateway1".to_string(),
+                    "gateway3".to_string(),
+                    Duration::from_millis(5),
+                    reachable_before,
+                )
+                .await
+                .unwrap();
+        }
+
+        let stats = gateway1.probe_peers(&gateway1.peers.list()).await;
+        assert!(stats.latencies.contains_key("gateway2"));
+        assert_eq!(stats.unreachable, vec!["gateway3".to_string()]);
+
+        gateway2
+            .handle_stats(Envelope::new(
+                "default",
+                Sender::gateway("gateway1"),
+                0,
+                Message::GatewayMeshStats(stats),
+            ))
+            .await
+            .unwrap();
+        for store in [&gateway1.store, &gateway2.store] {
+            assert!(store
+                .get_gateway_to_gateway_stats("gateway1", "gateway2")
+                .await
+                .unwrap()
+                .is_some());
+            assert!(store
+                .get_gateway_to_gateway_stats("gateway1", "gateway3")
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats_propagate_to_peer_routing() {
+        let broker = MemoryBroker::new();
+        let transport = || -> Arc<dyn PubSub> { Arc::new(MemoryPubSub::new(Arc::clone(&broker))) };
+
+        let (_service, service_port) = listener().await;
+        let (_proxy1, proxy1_port) = listener().await;
+        let (_proxy2, proxy2_port) = listener().await;
+
+        let orbit = Arc::new(Orbit::with_transport(
+            hcl::from_str::<OrbitConfig>(ORBIT_CONFIG).unwrap(),
+            transport(),
+        ));
+        let gateway1 = Arc::new(
+            Gateway::with_transport(
+                &gateway_config(
+                    "gateway1",
+                    proxy1_port,
+                    &format!(
+                        r#"{{
+                          id           = "llm"
+                          address      = "127.0.0.1"
+                          port         = {service_port}
+                          health_check = {{
+                            type     = "tcp"
+                            interval = "1s"
+                            timeout  = "1s"
+                          }}
+                        }}"#
+                    ),
+                ),
+                transport(),
+            )
+            .await
+            .unwrap(),
+        );
+        let gateway2 = Arc::new(
+            Gateway::with_transport(&gateway_config("gateway2", proxy2_port, ""), transport())
+                .await
+                .unwrap(),
+        );
+
+        let tasks = [
+            tokio::spawn({
+                let orbit = Arc::clone(&orbit);
+                async move { orbit.run().await }
+            }),
+            tokio::spawn(Arc::clone(&gateway1).run()),
+            tokio::spawn(Arc::clone(&gateway2).run()),
+        ];
+
+        let router1 = gateway1.router();
+        let router2 = gateway2.router();
+        let expected = Route::Peer {
+            gateway_id: "gateway1".to_string(),
+            address: format!("127.0.0.1:{}", proxy1_port),
+        };
+        let routed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if router2.route("llm", &[], &[]).await.as_ref() == Some(&expected) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+
+        // operators see the same through gateway2's store
+        let admin = Admin::with_transport(&hcl::from_str(ORBIT_CONFIG).unwrap(), transport());
+        let queried = admin.query_store("gateway2", "llm").await;
+        let pinged = admin.ping(Node::Gateway("gateway1")).await;
+        let pinged_orbit = admin.ping(Node::Orbit).await;
+
+        for task in tasks {
+            task.abort();
+        }
+        assert!(routed.is_ok(), "gateway2 never learned a route to llm");
+        let paths = queried.unwrap().paths;
+        assert_eq!(paths.first().map(|path| path.gateway()), Some("gateway1"));
+        assert!(pinged.is_ok());
+        assert!(pinged_orbit.is_ok());
+        assert_eq!(router1.route("llm", &[], &[]).await, Some(Route::Local));
+        assert_eq!(router2.route("chat", &[], &[]).await, None);
+    }
 }
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use super::gateway::Gateway;
+use crate::common::types::{GatewayHeartbeat, GatewayStatus, GatewayStatusUpdate};
+use crate::transport::pubsub::Message;
+use crate::transport::topics::PubSubTopics;
+
+impl Gateway {
+    pub(crate) fn spawn_heartbeat_sender(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning heartbeat sender");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_sending_heartbeats().await })
+    }
+
+    pub(crate) fn spawn_status_receiver(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning gateway status receiver");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_receiving_status().await })
+    }
+
+    // publishes a heartbeat every interval. Failures are logged rather than
+    // fatal, so a broker outage doesn't take the data plane down with it,
+    // orbit takes the gateway for dead until heartbeats get through again
+    async fn start_sending_heartbeats(&self) -> Result<()> {
+        info!("starting sending heartbeats");
+        let conf = &self.gateway_config.gateway;
+        let mut interval = tokio::time::interval(conf.heartbeat.interval);
+        let mut failures = 0;
+
+        loop {
+            interval.tick().await;
+
+            let heartbeat = GatewayHeartbeat {
+                gateway_id: self.id.clone(),
+                region: conf.region.clone(),
+                address: conf.advertise_address(),
+            };
+            let res = tokio::time::timeout(
+                conf.heartbeat.timeout,
+                self.transport.broadcast(
+                    &[self.publish_topic(PubSubTopics::PublishGatewayHeartbeat)],
+                    Message::GatewayHeartbeat(heartbeat),
+                ),
+            )
+            .await;
+
+            match res {
+                Ok(Ok(())) => {
+                    if failures > conf.heartbeat.retries {
+                        info!("heartbeats get through again after {} failures", failures);
+                    }
+                    failures = 0;
+                }
+                Ok(Err(e)) => {
+                    failures += 1;
+                    warn!("failed to publish heartbeat: {}", e);
+                }
+                Err(_) => {
+                    failures += 1;
+                    warn!("timed out publishing heartbeat");
+                }
+            }
+
+            if failures == conf.heartbeat.retries + 1 {
+                error!(
+                    "failed to publish {} consecutive heartbeats, orbit will take this gateway for dead",
+                    failures
+                );
+            }
+        }
+    }
+
+    async fn start_receiving_status(&self) -> Result<()> {
+        info!("starting receiving gateway status");
+        let mut rcv = self
+            .transport
+            .subscribe_to_topics(&self.subscribe_topics(PubSubTopics::SubscribeGatewayHeartbeat))
+            .await
+            .context("Failed to subscribe to topics")?;
+
+        while let Some(envelope) = rcv.recv().await {
+            if let Message::GatewayStatus(update) = envelope.message {
+                self.handle_gateway_status(update).await;
+            }
+        }
+        bail!("subscription to gateway status lost")
+    }
+
+    async fn handle_gateway_status(&self, update: GatewayStatusUpdate) {
+        if update.gateway_id == self.id {
+            return;
+        }
+        match update.status {
+            GatewayStatus::Dead => {
+                warn!(
+                    "gateway {} is dead, removing it from routing",
+                    update.gateway_id
+                );
+                self.peers.remove(&update.gateway_id);
+                if let Err(e) = self.store.remove_gateway(&update.gateway_id).await {
+                    warn!("failed to remove gateway {}: {}", update.gateway_id, e);
+                }
+            }
+            GatewayStatus::Alive => info!("gateway {} is alive", update.gateway_id),
+        }
+    }
+}
@@ -25,19 +25,33 @@ async fn get_http_latency(url: &str) -> Result<u64, std::io::Error> {
         .get(url)
         .send()
         .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        .map_err(std::io::Error::other)?;
     let latency = start.elapsed().as_millis() as u64;
     trace!("http latency measurement complete: {} ms", latency);
     Ok(latency)
 }
 
+// measures the round trip to a peer gateway's proxy listener
+#[instrument(level = "trace")]
+pub async fn get_gateway_latency(
+    addr: &str,
+    timeout: Duration,
+) -> Result<Duration, std::io::Error> {
+    trace!("measuring gateway latency for address: {}", addr);
+    let latency = tokio::time::timeout(timeout, get_tcp_latency(addr))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "gateway probe timed out")
+        })??;
+    Ok(Duration::from_millis(latency))
+}
+
 #[instrument(level = "trace", skip(srv))]
 pub async fn get_service_latency(srv: &ServiceConfig) -> ServiceStat {
     trace!("getting service latency for service id: {}", srv.id);
     let latency = match srv.health_check.r#type {
         HealthCheckType::Tcp => {
-            let addr = format!("{}:{}", srv.address, srv.port);
-            let res = get_tcp_latency(&addr).await;
+            let res = get_tcp_latency(&srv.upstream_addr()).await;
             match res {
                 Ok(latency) => latency,
                 Err(e) => {
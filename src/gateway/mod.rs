@@ -1,7 +1,12 @@
 pub mod config;
+pub mod failover;
+#[allow(clippy::module_inception)]
 pub mod gateway;
 pub mod health;
+pub mod heartbeat;
 pub mod latency;
+pub mod peers;
 pub mod pingora;
+pub mod requests;
 pub mod router;
 pub mod store;
@@ -0,0 +1,50 @@
+use std::{collections::HashMap, sync::RwLock, time::SystemTime};
+
+use tracing::{debug, trace};
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub id: String,
+    pub address: String,
+    pub last_seen: SystemTime,
+}
+
+// Directory of the other gateways in the cluster, keyed by gateway id
+#[derive(Debug, Default)]
+pub struct Peers {
+    peers: RwLock<HashMap<String, Peer>>,
+}
+
+impl Peers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn upsert(&self, id: &str, address: &str) {
+        trace!("updating peer: {} at {}", id, address);
+        let mut peers = self.peers.write().unwrap();
+        if !peers.contains_key(id) {
+            debug!("discovered peer gateway: {} at {}", id, address);
+        }
+        peers.insert(
+            id.to_string(),
+            Peer {
+                id: id.to_string(),
+                address: address.to_string(),
+                last_seen: SystemTime::now(),
+            },
+        );
+    }
+
+    pub fn get(&self, id: &str) -> Option<Peer> {
+        self.peers.read().unwrap().get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Peer> {
+        self.peers.write().unwrap().remove(id)
+    }
+
+    pub fn list(&self) -> Vec<Peer> {
+        self.peers.read().unwrap().values().cloned().collect()
+    }
+}
@@ -1,73 +1,287 @@
 use async_trait::async_trait;
-use pingora::http::RequestHeader;
+use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::services::background::background_service;
-use std::{sync::Arc, time::Duration};
-use tracing::info;
+use std::{collections::HashMap, sync::Arc};
+use tracing::{debug, info};
 
 use pingora::lb::{health_check, selection::RoundRobin, LoadBalancer};
 use pingora::proxy::{http_proxy_service, ProxyHttp, Session};
 use pingora::server::configuration::Opt;
 use pingora::server::Server;
 use pingora::upstreams::peer::HttpPeer;
-use pingora::Result;
+use pingora::{Error, ErrorSource, ErrorType, Result};
 
-pub struct LB(Arc<LoadBalancer<RoundRobin>>);
+use super::config::{GatewayConfig, ServiceConfig};
+use super::router::{Route, Router, SERVICE_HEADER, VIA_HEADER};
+use crate::common::types::GatewayFailoverEvent;
+
+pub struct ServiceUpstream {
+    config: ServiceConfig,
+    upstreams: Arc<LoadBalancer<RoundRobin>>,
+}
+
+pub struct LB {
+    router: Router,
+    services: HashMap<String, ServiceUpstream>,
+}
+
+#[derive(Debug, Default)]
+pub struct ProxyCtx {
+    service_id: Option<String>,
+    route: Option<Route>,
+    via: Vec<String>,
+    // gateways this request was sent to, in order
+    tried: Vec<String>,
+    attempts: u32,
+}
+
+impl LB {
+    fn local_peer(&self, service_id: &str) -> Result<Box<HttpPeer>> {
+        let service = self.services.get(service_id).ok_or_else(|| {
+            Error::explain(
+                ErrorType::HTTPStatus(502),
+                format!("service not available on this gateway: {}", service_id),
+            )
+        })?;
+
+        let upstream = service.upstreams.select(b"", 256).ok_or_else(|| {
+            Error::explain(
+                ErrorType::HTTPStatus(503),
+                format!("no healthy upstream for service: {}", service_id),
+            )
+        })?;
+
+        debug!(
+            "upstream peer for service {} is: {:?}",
+            service_id, upstream
+        );
+        Ok(Box::new(HttpPeer::new(
+            upstream,
+            service.config.tls,
+            service.config.upstream_host().to_string(),
+        )))
+    }
+
+    fn can_failover(&self, ctx: &ProxyCtx) -> bool {
+        ctx.attempts <= self.router.failover().retries()
+    }
+
+    fn report_failure(&self, service_id: &str, failed_gateway: &str, reason: String) {
+        self.router.failover().report(GatewayFailoverEvent {
+            gateway_id: self.router.gateway_id().to_string(),
+            service_id: service_id.to_string(),
+            failed_gateway: failed_gateway.to_string(),
+            reason,
+        });
+    }
+
+    fn report_ctx_failure(&self, ctx: &ProxyCtx, reason: String) {
+        if let (Some(service_id), Some(failed_gateway)) = (&ctx.service_id, ctx.tried.last()) {
+            self.report_failure(service_id, failed_gateway, reason);
+        }
+    }
+}
 
 #[async_trait]
 impl ProxyHttp for LB {
-    type CTX = ();
-    fn new_ctx(&self) -> Self::CTX {}
+    type CTX = ProxyCtx;
+    fn new_ctx(&self) -> Self::CTX {
+        ProxyCtx::default()
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        let service_id = self
+            .router
+            .resolve_service(session.req_header())
+            .await
+            .ok_or_else(|| {
+                Error::explain(
+                    ErrorType::HTTPStatus(404),
+                    "no service configured for request",
+                )
+            })?;
 
-    async fn upstream_peer(&self, _session: &mut Session, _ctx: &mut ()) -> Result<Box<HttpPeer>> {
-        let upstream = self
-            .0
-            .select(b"", 256) // hash doesn't matter
-            .unwrap();
+        let via = Router::parse_via(session.req_header());
+        ctx.service_id = Some(service_id.clone());
 
-        info!("upstream peer is: {:?}", upstream);
+        // every failing route ends up in `tried`, so this always terminates
+        loop {
+            let route = self
+                .router
+                .route(&service_id, &via, &ctx.tried)
+                .await
+                .ok_or_else(|| {
+                    Error::explain(
+                        ErrorType::HTTPStatus(502),
+                        format!("no route to service: {}", service_id),
+                    )
+                })?;
 
-        let peer = Box::new(HttpPeer::new(upstream, true, "one.one.one.one".to_string()));
-        Ok(peer)
+            let peer = match &route {
+                Route::Local => {
+                    ctx.tried.push(self.router.gateway_id().to_string());
+                    match self.local_peer(&service_id) {
+                        Ok(peer) => peer,
+                        Err(e) => {
+                            self.report_failure(
+                                &service_id,
+                                self.router.gateway_id(),
+                                e.to_string(),
+                            );
+                            continue;
+                        }
+                    }
+                }
+                Route::Peer {
+                    gateway_id,
+                    address,
+                } => {
+                    debug!(
+                        "forwarding service {} to gateway {} at {}",
+                        service_id, gateway_id, address
+                    );
+                    ctx.tried.push(gateway_id.clone());
+                    Box::new(HttpPeer::new(address.as_str(), false, String::new()))
+                }
+            };
+
+            ctx.attempts += 1;
+            ctx.route = Some(route);
+            ctx.via = via;
+            return Ok(peer);
+        }
     }
 
     async fn upstream_request_filter(
         &self,
         _session: &mut Session,
         upstream_request: &mut RequestHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let Some(service_id) = ctx.service_id.as_ref() else {
+            return Ok(());
+        };
+
+        match ctx.route {
+            Some(Route::Local) => {
+                if let Some(service) = self.services.get(service_id) {
+                    upstream_request.insert_header("Host", service.config.upstream_host())?;
+                }
+            }
+            // pin the resolved service so the peer doesn't have to resolve it
+            // again and record this gateway as a hop for loop prevention
+            Some(Route::Peer { .. }) => {
+                upstream_request.insert_header(SERVICE_HEADER, service_id.as_str())?;
+                let mut via = ctx.via.clone();
+                via.push(self.router.gateway_id().to_string());
+                upstream_request.insert_header(VIA_HEADER, via.join(","))?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
     ) -> Result<()> {
-        upstream_request
-            .insert_header("Host", "one.one.one.one")
-            .unwrap();
+        let status = upstream_response.status.as_u16();
+        if status >= 500 {
+            // turn the response into an error so the request is retried on
+            // the next path, it's reported from error_while_proxy
+            if self.can_failover(ctx) {
+                return Error::e_explain(
+                    ErrorType::HTTPStatus(status),
+                    "upstream responded with server error",
+                );
+            }
+            self.report_ctx_failure(ctx, format!("upstream responded with status {}", status));
+        }
         Ok(())
     }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        self.report_ctx_failure(ctx, e.to_string());
+        if self.can_failover(ctx) {
+            e.set_retry(true);
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        let replayable = !session.as_ref().retry_buffer_truncated();
+
+        // a client going away says nothing about the path
+        if e.esource != ErrorSource::Downstream {
+            self.report_ctx_failure(ctx, e.to_string());
+            if self.can_failover(ctx) && replayable {
+                e.set_retry(true);
+                return e;
+            }
+        }
+
+        // only reused client connections where retry buffer is not truncated
+        e.retry.decide_reuse(client_reused && replayable);
+        e
+    }
 }
 
-pub fn run_pingora() {
+pub fn run_pingora(conf: GatewayConfig, router: Router) -> anyhow::Result<()> {
     env_logger::init();
 
     // read command line arguments
     let opt = Opt::parse_args();
-    let mut my_server = Server::new(Some(opt)).unwrap();
+    let mut my_server = Server::new(Some(opt))?;
     my_server.bootstrap();
 
-    // 127.0.0.1:343" is just a bad server
-    let mut upstreams =
-        LoadBalancer::try_from_iter(["1.1.1.1:443", "1.0.0.1:443", "127.0.0.1:343"]).unwrap();
+    let mut services = HashMap::new();
+    for service in &conf.gateway.services {
+        let mut upstreams = LoadBalancer::try_from_iter([service.upstream_addr()])?;
 
-    // We add health check in the background so that the bad server is never selected.
-    let hc = health_check::TcpHealthCheck::new();
-    upstreams.set_health_check(hc);
-    upstreams.health_check_frequency = Some(Duration::from_secs(1));
+        // health check in the background so that a dead upstream is never selected
+        let hc = if service.tls {
+            health_check::TcpHealthCheck::new_tls(service.upstream_host())
+        } else {
+            health_check::TcpHealthCheck::new()
+        };
+        upstreams.set_health_check(hc);
+        upstreams.health_check_frequency = Some(service.health_check.interval);
 
-    let background = background_service("health check", upstreams);
-
-    let upstreams = background.task();
+        let background = background_service(&format!("health check {}", service.id), upstreams);
+        services.insert(
+            service.id.clone(),
+            ServiceUpstream {
+                config: service.clone(),
+                upstreams: background.task(),
+            },
+        );
+        my_server.add_service(background);
+        info!("added upstream pool for service: {}", service.id);
+    }
 
-    let mut lb = http_proxy_service(&my_server.configuration, LB(upstreams));
-    lb.add_tcp("0.0.0.0:6188");
+    let mut lb = http_proxy_service(&my_server.configuration, LB { router, services });
+    lb.add_tcp(&format!("0.0.0.0:{}", conf.gateway.listen_port));
 
     my_server.add_service(lb);
-    my_server.add_service(background);
     my_server.run_forever();
 }
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use super::gateway::Gateway;
+use crate::common::types::{StorePaths, StoreQuery};
+use crate::transport::pubsub::{Envelope, Message};
+use crate::transport::topics::Topic;
+
+impl Gateway {
+    pub(crate) fn spawn_request_handler(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning request handler");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_answering_requests().await })
+    }
+
+    // answers pings and queries of the store, e.g. from orbit
+    async fn start_answering_requests(&self) -> Result<()> {
+        info!("starting answering requests");
+        let mut rcv = self
+            .transport
+            .subscribe_to_topics(&[Topic::gateway_requests(&self.id)])
+            .await
+            .context("Failed to subscribe to topics")?;
+
+        while let Some(request) = rcv.recv().await {
+            let reply = self.handle_request(&request).await;
+            if let Err(e) = self.transport.reply(&request, reply).await {
+                warn!(
+                    "failed to reply to request from {}: {}",
+                    request.sender.id, e
+                );
+            }
+        }
+        bail!("subscription to requests lost")
+    }
+
+    async fn handle_request(&self, request: &Envelope) -> Message {
+        match &request.message {
+            Message::Ping => Message::Pong,
+            Message::StoreQuery(StoreQuery { service_id }) => {
+                match self.store.get_service_paths(service_id).await {
+                    Ok(paths) => Message::StorePaths(StorePaths {
+                        gateway_id: self.id.clone(),
+                        service_id: service_id.clone(),
+                        paths,
+                    }),
+                    Err(e) => Message::RequestFailed(format!("failed to query store: {}", e)),
+                }
+            }
+            _ => Message::RequestFailed("not a request a gateway answers".to_string()),
+        }
+    }
+}
@@ -1,19 +1,489 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use anyhow::Result;
-use tracing::info;
+use pingora::http::RequestHeader;
+use tracing::{debug, error, info, trace, warn};
 
 use crate::gateway::pingora::run_pingora;
+use crate::gateway::store::store::Store as StoreTrait;
 
+use super::failover::Failover;
 use super::gateway::Gateway;
+use super::peers::Peers;
+
+pub const SERVICE_HEADER: &str = "x-pluto-service";
+// comma separated ids of the gateways a request was forwarded through
+pub const VIA_HEADER: &str = "x-pluto-via";
+pub const DEFAULT_MAX_HOPS: usize = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    Local,
+    Peer { gateway_id: String, address: String },
+}
+
+#[derive(Debug)]
+pub struct Router {
+    gateway_id: String,
+    max_hops: usize,
+    local_services: HashSet<String>,
+    store: Arc<dyn StoreTrait>,
+    peers: Arc<Peers>,
+    failover: Arc<Failover>,
+}
+
+impl Router {
+    pub fn new(
+        gateway_id: String,
+        max_hops: usize,
+        local_services: HashSet<String>,
+        store: Arc<dyn StoreTrait>,
+        peers: Arc<Peers>,
+        failover: Arc<Failover>,
+    ) -> Self {
+        Self {
+            gateway_id,
+            max_hops,
+            local_services,
+            store,
+            peers,
+            failover,
+        }
+    }
+
+    pub fn gateway_id(&self) -> &str {
+        &self.gateway_id
+    }
+
+    pub fn failover(&self) -> &Failover {
+        &self.failover
+    }
+
+    pub fn parse_via(req: &RequestHeader) -> Vec<String> {
+        req.headers
+            .get_all(VIA_HEADER)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    async fn is_known_service(&self, service_id: &str) -> bool {
+        if self.local_services.contains(service_id) {
+            return true;
+        }
+        match self.store.get_optimal_service_path(service_id).await {
+            Ok(path) => path.is_some(),
+            Err(e) => {
+                warn!("failed to look up service {}: {}", service_id, e);
+                false
+            }
+        }
+    }
+
+    // resolves the target service from the request, in order of precedence:
+    // the service header, the host (full hostname or first label) and the
+    // first segment of the path
+    pub async fn resolve_service(&self, req: &RequestHeader) -> Option<String> {
+        if let Some(service_id) = req
+            .headers
+            .get(SERVICE_HEADER)
+            .and_then(|h| h.to_str().ok())
+        {
+            return Some(service_id.to_string());
+        }
+
+        let host = req
+            .headers
+            .get("Host")
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri.host());
+        if let Some(host) = host {
+            let hostname = host.split(':').next().unwrap_or(host);
+            let label = hostname.split('.').next().unwrap_or(hostname);
+            for candidate in [hostname, label] {
+                if self.is_known_service(candidate).await {
+                    return Some(candidate.to_string());
+                }
+            }
+        }
+
+        let segment = req.uri.path().trim_start_matches('/').split('/').next()?;
+        if self.is_known_service(segment).await {
+            return Some(segment.to_string());
+        }
+        None
+    }
+
+    // picks where to send a request for the service, walking the paths the
+    // store knows from best to worst and falling back to the local service.
+    // a request is only sent to the next hop of a path, which routes it
+    // further on its own.
+    // `via` lists the gateways the request was already forwarded through,
+    // once it reaches `max_hops` or a gateway already saw the request it is
+    // not sent there again so that gateways never bounce it around.
+    // `tried` lists the gateways that already failed this request, paths
+    // that recently failed other requests are skipped as well
+    pub async fn route(&self, service_id: &str, via: &[String], tried: &[String]) -> Option<Route> {
+        if via.len() >= self.max_hops || via.contains(&self.gateway_id) {
+            trace!(
+                "request for service {} already forwarded through {:?}, serving locally",
+                service_id,
+                via
+            );
+            return self.local_route(service_id, tried);
+        }
+
+        // without paths the request can still be served locally
+        let paths = self
+            .store
+            .get_service_paths(service_id)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("failed to get paths for service {}: {}", service_id, e);
+                Vec::new()
+            });
+        for path in paths {
+            let gateway_id = path.gateway();
+            let next_hop = path.next_hop();
+            if [gateway_id, next_hop].iter().any(|id| {
+                tried.iter().any(|tried_id| tried_id == id)
+                    || self.failover.is_failed(id, service_id)
+            }) {
+                trace!(
+                    "skipping failed path {:?} for service {}",
+                    path.hops,
+                    service_id
+                );
+                continue;
+            }
+            if gateway_id == self.gateway_id {
+                match self.local_route(service_id, tried) {
+                    Some(route) => return Some(route),
+                    None => continue,
+                }
+            }
+            // every gateway after this one forwards the request once more
+            if via.len() + path.hops.len() - 1 > self.max_hops {
+                trace!(
+                    "path {:?} for service {} exceeds the hop limit",
+                    path.hops,
+                    service_id
+                );
+                continue;
+            }
+            if path.hops.iter().any(|hop| via.contains(hop)) {
+                debug!(
+                    "path {:?} for service {} goes through a gateway that already forwarded the request, skipping",
+                    path.hops, service_id
+                );
+                continue;
+            }
+            match self.peers.get(next_hop) {
+                Some(peer) => {
+                    trace!(
+                        "routing service {} through {:?} ({:?})",
+                        service_id,
+                        path.hops,
+                        path.latency
+                    );
+                    return Some(Route::Peer {
+                        gateway_id: next_hop.to_string(),
+                        address: peer.address,
+                    });
+                }
+                None => warn!(
+                    "next hop {} for service {} has no known address",
+                    next_hop, service_id
+                ),
+            }
+        }
+
+        self.local_route(service_id, tried)
+    }
+
+    fn local_route(&self, service_id: &str, tried: &[String]) -> Option<Route> {
+        (self.local_services.contains(service_id) && !tried.contains(&self.gateway_id))
+            .then_some(Route::Local)
+    }
+}
 
 impl Gateway {
+    pub fn router(&self) -> Router {
+        Router::new(
+            self.id.clone(),
+            self.gateway_config
+                .gateway
+                .max_hops
+                .unwrap_or(DEFAULT_MAX_HOPS),
+            self.services.keys().cloned().collect(),
+            Arc::clone(&self.store),
+            Arc::clone(&self.peers),
+            Arc::clone(&self.failover),
+        )
+    }
+
     pub fn start_pingora_server(self: &Arc<Self>) -> Result<()> {
-        std::thread::spawn(|| {
+        let conf = self.gateway_config.clone();
+        let router = self.router();
+        std::thread::spawn(move || {
             info!("starting pingora server");
-            run_pingora();
+            if let Err(e) = run_pingora(conf, router) {
+                error!("pingora server failed: {}", e);
+            }
         });
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::common::types::{
+        GatewayFailoverEvent, GatewayLatencyStats, ServiceStat, ServiceStatus,
+    };
+    use crate::gateway::config::FailoverConfig;
+    use crate::gateway::store::memory::InMemoryStore;
+
+    fn stats(gateway_id: &str, service_id: &str, latency: u64) -> GatewayLatencyStats {
+        let mut stats = GatewayLatencyStats {
+            gateway_id: gateway_id.to_string(),
+            address: format!("{}:8080", gateway_id),
+            stats: HashMap::new(),
+        };
+        stats.stats.insert(
+            service_id.to_string(),
+            ServiceStat {
+                service_id: service_id.to_string(),
+                status: ServiceStatus::Up,
+                latency: Duration::from_millis(latency),
+                error: None,
+            },
+        );
+        stats
+    }
+
+    fn router(local_services: &[&str]) -> (Router, Arc<dyn StoreTrait>, Arc<Peers>) {
+        router_with_max_hops(DEFAULT_MAX_HOPS, local_services)
+    }
+
+    fn router_with_max_hops(
+        max_hops: usize,
+        local_services: &[&str],
+    ) -> (Router, Arc<dyn StoreTrait>, Arc<Peers>) {
+        let store: Arc<dyn StoreTrait> = Arc::new(InMemoryStore::new("gateway1".to_string()));
+        let peers = Arc::new(Peers::new());
+        let router = Router::new(
+            "gateway1".to_string(),
+            max_hops,
+            local_services.iter().map(|s| s.to_string()).collect(),
+            Arc::clone(&store),
+            Arc::clone(&peers),
+            Arc::new(Failover::new(&FailoverConfig {
+                retries: 2,
+                interval: Duration::from_secs(60),
+            })),
+        );
+        (router, store, peers)
+    }
+
+    async fn link(store: &Arc<dyn StoreTrait>, from: &str, to: &str, latency: u64) {
+        store
+            .update_gateway_to_gateway_stats(
+                from.to_string(),
+                to.to_string(),
+                Duration::from_millis(latency),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resolve_service() {
+        let (router, _, _) = router(&["llm", "chat"]);
+
+        let mut req = RequestHeader::build("GET", b"/v1/completions", None).unwrap();
+        req.insert_header(SERVICE_HEADER, "chat").unwrap();
+        assert_eq!(router.resolve_service(&req).await, Some("chat".to_string()));
+
+        let mut req = RequestHeader::build("GET", b"/v1/completions", None).unwrap();
+        req.insert_header("Host", "llm.example.com:8080").unwrap();
+        assert_eq!(router.resolve_service(&req).await, Some("llm".to_string()));
+
+        let req = RequestHeader::build("GET", b"/chat/v1/messages", None).unwrap();
+        assert_eq!(router.resolve_service(&req).await, Some("chat".to_string()));
+
+        let req = RequestHeader::build("GET", b"/unknown", None).unwrap();
+        assert_eq!(router.resolve_service(&req).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_route_prefers_optimal_gateway() {
+        let (router, store, peers) = router(&["llm"]);
+        store
+            .update_gateway_to_service_stats(stats("gateway1", "llm", 50), SystemTime::now())
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(stats("gateway2", "llm", 10), SystemTime::now())
+            .await
+            .unwrap();
+        link(&store, "gateway1", "gateway2", 5).await;
+
+        // without an address for gateway2 the request is served locally
+        assert_eq!(router.route("llm", &[], &[]).await, Some(Route::Local));
+
+        peers.upsert("gateway2", "gateway2:8080");
+        assert_eq!(
+            router.route("llm", &[], &[]).await,
+            Some(Route::Peer {
+                gateway_id: "gateway2".to_string(),
+                address: "gateway2:8080".to_string(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_unknown_service() {
+        let (router, _, _) = router(&["llm"]);
+        assert_eq!(router.route("llm", &[], &[]).await, Some(Route::Local));
+        assert_eq!(router.route("chat", &[], &[]).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_route_forwarded_request_is_served_locally() {
+        let (router, store, peers) = router(&["llm"]);
+        store
+            .update_gateway_to_service_stats(stats("gateway1", "llm", 50), SystemTime::now())
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(stats("gateway2", "llm", 10), SystemTime::now())
+            .await
+            .unwrap();
+        link(&store, "gateway1", "gateway2", 5).await;
+        peers.upsert("gateway2", "gateway2:8080");
+
+        // hop limit reached
+        let via = vec!["gateway3".to_string()];
+        assert_eq!(router.route("llm", &via, &[]).await, Some(Route::Local));
+
+        // the optimal gateway already forwarded the request to us
+        let via = vec!["gateway2".to_string()];
+        assert_eq!(router.route("llm", &via, &[]).await, Some(Route::Local));
+
+        // the request has to be served locally but the service isn't here
+        assert_eq!(router.route("chat", &via, &[]).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_route_fails_over_to_next_best_path() {
+        let (router, store, peers) = router(&["llm"]);
+        store
+            .update_gateway_to_service_stats(stats("gateway1", "llm", 50), SystemTime::now())
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(stats("gateway2", "llm", 10), SystemTime::now())
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(stats("gateway3", "llm", 20), SystemTime::now())
+            .await
+            .unwrap();
+        link(&store, "gateway1", "gateway2", 5).await;
+        link(&store, "gateway1", "gateway3", 5).await;
+        peers.upsert("gateway2", "gateway2:8080");
+        peers.upsert("gateway3", "gateway3:8080");
+
+        let tried = vec!["gateway2".to_string()];
+        assert_eq!(
+            router.route("llm", &[], &tried).await,
+            Some(Route::Peer {
+                gateway_id: "gateway3".to_string(),
+                address: "gateway3:8080".to_string(),
+            })
+        );
+
+        router.failover().report(GatewayFailoverEvent {
+            gateway_id: "gateway1".to_string(),
+            service_id: "llm".to_string(),
+            failed_gateway: "gateway3".to_string(),
+            reason: "connection refused".to_string(),
+        });
+        assert_eq!(router.route("llm", &[], &tried).await, Some(Route::Local));
+
+        let tried = vec![
+            "gateway2".to_string(),
+            "gateway3".to_string(),
+            "gateway1".to_string(),
+        ];
+        assert_eq!(router.route("llm", &[], &tried).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_route_multi_hop_path() {
+        let (router, store, peers) = router_with_max_hops(2, &["llm"]);
+        store
+            .update_gateway_to_service_stats(stats("gateway1", "llm", 100), SystemTime::now())
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(stats("gateway3", "llm", 10), SystemTime::now())
+            .await
+            .unwrap();
+        link(&store, "gateway1", "gateway2", 5).await;
+        link(&store, "gateway2", "gateway3", 5).await;
+        peers.upsert("gateway2", "gateway2:8080");
+
+        // gateway3 is only reachable through gateway2
+        assert_eq!(
+            router.route("llm", &[], &[]).await,
+            Some(Route::Peer {
+                gateway_id: "gateway2".to_string(),
+                address: "gateway2:8080".to_string(),
+            })
+        );
+
+        // forwarding once more would exceed the hop limit
+        let via = vec!["gateway4".to_string()];
+        assert_eq!(router.route("llm", &via, &[]).await, Some(Route::Local));
+
+        // a single hop isn't enough to reach gateway3
+        let (router, store, peers) = router_with_max_hops(1, &["llm"]);
+        store
+            .update_gateway_to_service_stats(stats("gateway1", "llm", 100), SystemTime::now())
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(stats("gateway3", "llm", 10), SystemTime::now())
+            .await
+            .unwrap();
+        link(&store, "gateway1", "gateway2", 5).await;
+        link(&store, "gateway2", "gateway3", 5).await;
+        peers.upsert("gateway2", "gateway2:8080");
+        assert_eq!(router.route("llm", &[], &[]).await, Some(Route::Local));
+    }
+
+    #[test]
+    fn test_parse_via() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(Router::parse_via(&req).is_empty());
+
+        req.insert_header(VIA_HEADER, "gateway1, gateway2,")
+            .unwrap();
+        assert_eq!(
+            Router::parse_via(&req),
+            vec!["gateway1".to_string(), "gateway2".to_string()]
+        );
+    }
+}
@@ -1,38 +1,43 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::RwLock,
     time::{Duration, SystemTime},
 };
 
+use anyhow::Result;
+use async_trait::async_trait;
 use tracing::{debug, info, trace, warn};
 
-use crate::common::types::GatewayLatencyStats;
+use crate::common::types::{GatewayLatencyStats, ServiceStatus};
+
+use crate::gateway::config::StoreConfig;
 
 use super::{
-    store::GatewayToGatewayStats, store::GatewayToServiceStats, store::OptimalPath, store::Store,
+    path::shortest_service_paths, store::GatewayToGatewayStats, store::GatewayToServiceStats,
+    store::OptimalPath, store::ServicePath, store::Store,
 };
 
 #[derive(Debug)]
 pub struct InMemoryStore {
+    // paths are calculated from this gateway
+    local_gateway: String,
     // Gateway ID -> Service ID -> Stats
     gateway_to_service: RwLock<HashMap<String, HashMap<String, GatewayToServiceStats>>>,
     // Gateway ID -> Gateway ID -> Stats
     gateway_to_gateway: RwLock<HashMap<String, HashMap<String, GatewayToGatewayStats>>>,
     // Service ID -> Optimal Path
     optimal_paths: RwLock<HashMap<String, OptimalPath>>,
+    service_stats_max_age: Duration,
+    gateway_stats_max_age: Duration,
 }
 
+#[async_trait]
 impl Store for InMemoryStore {
-    fn new() -> Self {
-        info!("creating new in-memory store");
-        InMemoryStore {
-            gateway_to_service: RwLock::new(HashMap::new()),
-            gateway_to_gateway: RwLock::new(HashMap::new()),
-            optimal_paths: RwLock::new(HashMap::new()),
-        }
-    }
-
-    fn update_gateway_to_service_stats(&self, stats: GatewayLatencyStats) {
+    async fn update_gateway_to_service_stats(
+        &self,
+        stats: GatewayLatencyStats,
+        reported_at: SystemTime,
+    ) -> Result<()> {
         trace!(
             "updating gateway-to-service stats for gateway: {}",
             stats.gateway_id
@@ -40,22 +45,36 @@ impl Store for InMemoryStore {
         let mut gateway_to_service = self.gateway_to_service.write().unwrap();
         let gateway_stats = gateway_to_service
             .entry(stats.gateway_id.clone())
-            .or_insert_with(HashMap::new);
+            .or_default();
 
-        let affected_services: Vec<String> = stats.stats.keys().cloned().collect();
+        let mut affected_services = Vec::new();
 
         for (service_id, service_stat) in stats.stats {
+            if gateway_stats
+                .get(&service_id)
+                .is_some_and(|existing| existing.reported_at > reported_at)
+            {
+                debug!(
+                    "discarding outdated stats for service {} from gateway {}",
+                    service_id, stats.gateway_id
+                );
+                continue;
+            }
             gateway_stats.insert(
                 service_id.clone(),
                 GatewayToServiceStats {
                     service_id: service_id.clone(),
                     latency: service_stat.latency,
+                    status: service_stat.status,
+                    error: service_stat.error,
                     last_updated: SystemTime::now(),
+                    reported_at,
                 },
             );
+            affected_services.push(service_id.clone());
             info!(
-                "updated stats for service: {} with latency: {:?}",
-                service_id, service_stat.latency
+                "updated stats for service: {} with status: {:?}, latency: {:?}",
+                service_id, service_stat.status, service_stat.latency
             );
         }
         drop(gateway_to_service);
@@ -64,29 +83,40 @@ impl Store for InMemoryStore {
         for service_id in affected_services {
             self.update_optimal_path(&service_id);
         }
+        Ok(())
     }
 
-    fn update_gateway_to_gateway_stats(
+    async fn update_gateway_to_gateway_stats(
         &self,
         from_gateway: String,
         to_gateway: String,
         latency: Duration,
-    ) {
+        reported_at: SystemTime,
+    ) -> Result<()> {
         trace!(
             "updating gateway-to-gateway stats: {} -> {}",
             from_gateway,
             to_gateway
         );
         let mut gateway_to_gateway = self.gateway_to_gateway.write().unwrap();
-        let gateway_stats = gateway_to_gateway
-            .entry(from_gateway.clone())
-            .or_insert_with(HashMap::new);
+        let gateway_stats = gateway_to_gateway.entry(from_gateway.clone()).or_default();
 
+        if gateway_stats
+            .get(&to_gateway)
+            .is_some_and(|existing| existing.reported_at > reported_at)
+        {
+            debug!(
+                "discarding outdated gateway-to-gateway stats: {} -> {}",
+                from_gateway, to_gateway
+            );
+            return Ok(());
+        }
         gateway_stats.insert(
             to_gateway.clone(),
             GatewayToGatewayStats {
                 latency,
                 last_updated: SystemTime::now(),
+                reported_at,
             },
         );
         drop(gateway_to_gateway);
@@ -97,181 +127,334 @@ impl Store for InMemoryStore {
         );
 
         // Update optimal paths for all services
-        let service_ids: Vec<String> = self.optimal_paths.read().unwrap().keys().cloned().collect();
-        for service_id in service_ids {
+        self.update_all_optimal_paths();
+        Ok(())
+    }
+
+    async fn remove_gateway_to_gateway_stats(
+        &self,
+        from_gateway: &str,
+        to_gateway: &str,
+        reported_at: SystemTime,
+    ) -> Result<()> {
+        trace!(
+            "removing gateway-to-gateway stats: {} -> {}",
+            from_gateway,
+            to_gateway
+        );
+        let removed = self
+            .gateway_to_gateway
+            .write()
+            .unwrap()
+            .get_mut(from_gateway)
+            .and_then(|gateways| {
+                // a stat reported after the removal stays
+                match gateways.get(to_gateway) {
+                    Some(existing) if existing.reported_at <= reported_at => {
+                        gateways.remove(to_gateway)
+                    }
+                    _ => None,
+                }
+            });
+        if removed.is_none() {
+            return Ok(());
+        }
+
+        debug!(
+            "removed gateway-to-gateway stats: {} -> {}",
+            from_gateway, to_gateway
+        );
+
+        // Update optimal paths for all services
+        self.update_all_optimal_paths();
+        Ok(())
+    }
+
+    async fn remove_gateway(&self, gateway_id: &str) -> Result<()> {
+        trace!("removing gateway: {}", gateway_id);
+        self.gateway_to_service.write().unwrap().remove(gateway_id);
+
+        let mut gateway_to_gateway = self.gateway_to_gateway.write().unwrap();
+        gateway_to_gateway.remove(gateway_id);
+        for gateways in gateway_to_gateway.values_mut() {
+            gateways.remove(gateway_id);
+        }
+        drop(gateway_to_gateway);
+
+        info!("removed gateway: {}", gateway_id);
+
+        // Update optimal paths for all services
+        self.update_all_optimal_paths();
+        Ok(())
+    }
+
+    async fn evict_expired(&self) -> Result<()> {
+        trace!("evicting expired stats");
+        let mut affected_services = HashSet::new();
+
+        let mut gateway_to_service = self.gateway_to_service.write().unwrap();
+        for (gateway_id, services) in gateway_to_service.iter_mut() {
+            services.retain(|service_id, stats| {
+                if stats.is_expired(self.service_stats_max_age) {
+                    debug!(
+                        "evicting expired stats of service {} on gateway {}",
+                        service_id, gateway_id
+                    );
+                    affected_services.insert(service_id.clone());
+                    return false;
+                }
+                true
+            });
+        }
+        gateway_to_service.retain(|_, services| !services.is_empty());
+        drop(gateway_to_service);
+
+        let mut evicted_edges = false;
+        let mut gateway_to_gateway = self.gateway_to_gateway.write().unwrap();
+        for (from_gateway, gateways) in gateway_to_gateway.iter_mut() {
+            gateways.retain(|to_gateway, stats| {
+                if stats.is_expired(self.gateway_stats_max_age) {
+                    debug!(
+                        "evicting expired gateway-to-gateway stats: {} -> {}",
+                        from_gateway, to_gateway
+                    );
+                    evicted_edges = true;
+                    return false;
+                }
+                true
+            });
+        }
+        gateway_to_gateway.retain(|_, gateways| !gateways.is_empty());
+        drop(gateway_to_gateway);
+
+        // any evicted edge can change the path of every service
+        if evicted_edges {
+            affected_services.extend(self.known_services());
+        }
+        for service_id in affected_services {
             self.update_optimal_path(&service_id);
         }
+        Ok(())
     }
 
-    fn get_optimal_service_path(&self, service_id: &str) -> Option<(String, Duration)> {
+    async fn get_optimal_service_path(&self, service_id: &str) -> Result<Option<ServicePath>> {
         trace!("getting optimal service path for service: {}", service_id);
-        self.optimal_paths
+        // don't hand out a path the sweeper hasn't caught up with yet
+        let Some(gateway) = self
+            .optimal_paths
+            .read()
+            .unwrap()
+            .get(service_id)
+            .map(|optimal_path| optimal_path.path.gateway().to_string())
+        else {
+            return Ok(None);
+        };
+        let expired = self
+            .gateway_to_service
+            .read()
+            .unwrap()
+            .get(&gateway)
+            .and_then(|services| services.get(service_id))
+            .is_some_and(|stats| stats.is_expired(self.service_stats_max_age));
+        if expired {
+            self.update_optimal_path(service_id);
+        }
+
+        Ok(self
+            .optimal_paths
             .read()
             .unwrap()
             .get(service_id)
             .map(|optimal_path| {
                 trace!(
-                    "found optimal path for service: {}. gateway: {}, latency: {:?}",
+                    "found optimal path for service: {}. hops: {:?}, latency: {:?}",
                     service_id,
-                    optimal_path.gateway,
-                    optimal_path.latency
+                    optimal_path.path.hops,
+                    optimal_path.path.latency
                 );
-                (optimal_path.gateway.clone(), optimal_path.latency)
-            })
+                optimal_path.path.clone()
+            }))
     }
 
-    fn get_gateway_to_service_stats(
+    async fn get_service_paths(&self, service_id: &str) -> Result<Vec<ServicePath>> {
+        trace!("getting service paths for service: {}", service_id);
+        Ok(self.calculate_service_paths(service_id))
+    }
+
+    async fn get_gateway_to_service_stats(
         &self,
         gateway_id: &str,
         service_id: &str,
-    ) -> Option<GatewayToServiceStats> {
+    ) -> Result<Option<GatewayToServiceStats>> {
         trace!(
             "getting gateway-to-service stats for gateway: {}, service: {}",
             gateway_id,
             service_id
         );
-        self.gateway_to_service
+        Ok(self
+            .gateway_to_service
             .read()
             .unwrap()
             .get(gateway_id)
             .and_then(|services| services.get(service_id).cloned())
-            .map(|stats| {
+            .filter(|stats| !stats.is_expired(self.service_stats_max_age))
+            .inspect(|stats| {
                 trace!(
                     "found gateway-to-service stats. latency: {:?}",
                     stats.latency
                 );
-                stats
-            })
+            }))
     }
 
-    fn get_gateway_to_gateway_stats(
+    async fn get_gateway_to_gateway_stats(
         &self,
         from_gateway: &str,
         to_gateway: &str,
-    ) -> Option<GatewayToGatewayStats> {
+    ) -> Result<Option<GatewayToGatewayStats>> {
         trace!(
             "getting gateway-to-gateway stats for: {} -> {}",
             from_gateway,
             to_gateway
         );
-        self.gateway_to_gateway
+        Ok(self
+            .gateway_to_gateway
             .read()
             .unwrap()
             .get(from_gateway)
             .and_then(|gateways| gateways.get(to_gateway).cloned())
-            .map(|stats| {
+            .filter(|stats| !stats.is_expired(self.gateway_stats_max_age))
+            .inspect(|stats| {
                 trace!(
                     "found gateway-to-gateway stats. latency: {:?}",
                     stats.latency
                 );
-                stats
-            })
+            }))
     }
 }
 
 impl InMemoryStore {
+    pub fn new(local_gateway: String) -> Self {
+        Self::with_config(local_gateway, &StoreConfig::default())
+    }
+
+    pub fn with_config(local_gateway: String, conf: &StoreConfig) -> Self {
+        info!("creating new in-memory store");
+        InMemoryStore {
+            local_gateway,
+            gateway_to_service: RwLock::new(HashMap::new()),
+            gateway_to_gateway: RwLock::new(HashMap::new()),
+            optimal_paths: RwLock::new(HashMap::new()),
+            service_stats_max_age: conf.service_stats_max_age,
+            gateway_stats_max_age: conf.gateway_stats_max_age,
+        }
+    }
+
+    // a stat is usable for routing only while it's up and fresh
+    fn is_usable(&self, stats: &GatewayToServiceStats) -> bool {
+        stats.status == ServiceStatus::Up && !stats.is_expired(self.service_stats_max_age)
+    }
+
+    fn known_services(&self) -> HashSet<String> {
+        let mut service_ids: HashSet<String> = self
+            .gateway_to_service
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|services| services.keys().cloned())
+            .collect();
+        service_ids.extend(self.optimal_paths.read().unwrap().keys().cloned());
+        service_ids
+    }
+
+    // a mesh change can make any service reachable or unreachable, not only
+    // the ones that already have a path
+    fn update_all_optimal_paths(&self) {
+        for service_id in self.known_services() {
+            self.update_optimal_path(&service_id);
+        }
+    }
+
     fn update_optimal_path(&self, service_id: &str) {
         trace!("updating optimal path for service: {}", service_id);
-        if let Some((gateway, latency)) = self.calculate_optimal_service_path(service_id) {
-            let mut optimal_paths = self.optimal_paths.write().unwrap();
-            optimal_paths.insert(
+        if let Some(path) = self.calculate_service_paths(service_id).into_iter().next() {
+            debug!(
+                "updated optimal path for service: {}. hops: {:?}, latency: {:?}",
+                service_id, path.hops, path.latency
+            );
+            self.optimal_paths.write().unwrap().insert(
                 service_id.to_string(),
                 OptimalPath {
-                    gateway: gateway.clone(),
-                    latency,
+                    path,
                     last_updated: SystemTime::now(),
                 },
             );
-            debug!(
-                "updated optimal path for service: {}. gateway: {}, latency: {:?}",
-                service_id, gateway, latency
-            );
         } else {
             warn!(
                 "failed to calculate optimal path for service: {}",
                 service_id
             );
+            self.optimal_paths.write().unwrap().remove(service_id);
         }
     }
 
-    fn calculate_optimal_service_path(&self, service_id: &str) -> Option<(String, Duration)> {
-        trace!(
-            "calculating optimal service path for service: {}",
-            service_id
-        );
-        let mut best_gateway = None;
-        let mut best_latency = Duration::MAX;
-
-        let gateway_to_service = self.gateway_to_service.read().unwrap();
-        let gateway_to_gateway = self.gateway_to_gateway.read().unwrap();
-
-        // Check all gateways for direct connections
-        for (gateway_id, services) in &*gateway_to_service {
-            if let Some(service_stats) = services.get(service_id) {
-                if service_stats.latency < best_latency {
-                    best_gateway = Some(gateway_id.clone());
-                    best_latency = service_stats.latency;
-                    trace!(
-                        "found better direct path through gateway: {}. latency: {:?}",
-                        gateway_id,
-                        best_latency
-                    );
-                }
-            }
+    // shortest paths from the local gateway over the fresh mesh to every
+    // gateway where the service is up
+    fn calculate_service_paths(&self, service_id: &str) -> Vec<ServicePath> {
+        trace!("calculating service paths for service: {}", service_id);
+        let services: HashMap<String, Duration> = self
+            .gateway_to_service
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|(gateway_id, services)| {
+                services
+                    .get(service_id)
+                    .filter(|stats| self.is_usable(stats))
+                    .map(|stats| (gateway_id.clone(), stats.latency))
+            })
+            .collect();
+        if services.is_empty() {
+            return Vec::new();
         }
 
-        // Check paths through other gateways
-        for (from_gateway, gateway_stats) in &*gateway_to_gateway {
-            for (intermediate_gateway, gateway_to_gateway_stats) in gateway_stats {
-                if let Some(service_stats) = gateway_to_service
-                    .get(intermediate_gateway)
-                    .and_then(|services| services.get(service_id))
-                {
-                    let total_latency = gateway_to_gateway_stats.latency + service_stats.latency;
-                    if total_latency < best_latency {
-                        best_latency = total_latency;
-                        best_gateway = Some(intermediate_gateway.clone());
-                        trace!(
-                            "found better path through gateways: {} -> {}. total latency: {:?}",
-                            from_gateway,
-                            intermediate_gateway,
-                            total_latency
-                        );
-                    }
-                }
-            }
-        }
+        let mesh: HashMap<String, HashMap<String, Duration>> = self
+            .gateway_to_gateway
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(from_gateway, gateways)| {
+                let latencies = gateways
+                    .iter()
+                    .filter(|(_, stats)| !stats.is_expired(self.gateway_stats_max_age))
+                    .map(|(to_gateway, stats)| (to_gateway.clone(), stats.latency))
+                    .collect();
+                (from_gateway.clone(), latencies)
+            })
+            .collect();
 
-        best_gateway.map(|gateway| {
-            debug!(
-                "calculated optimal path for service: {}. gateway: {}, latency: {:?}",
-                service_id, gateway, best_latency
-            );
-            (gateway, best_latency)
-        })
+        shortest_service_paths(&self.local_gateway, &mesh, &services)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::common::types::ServiceStatus;
-
     use super::*;
     use std::time::Duration;
 
     #[test]
     fn test_new_store() {
-        let store = InMemoryStore::new();
+        let store = InMemoryStore::new("gateway1".to_string());
         assert!(store.gateway_to_service.read().unwrap().is_empty());
         assert!(store.gateway_to_gateway.read().unwrap().is_empty());
         assert!(store.optimal_paths.read().unwrap().is_empty());
     }
 
-    #[test]
-    fn test_update_gateway_to_service_stats() {
-        let store = InMemoryStore::new();
+    #[tokio::test]
+    async fn test_update_gateway_to_service_stats() {
+        let store = InMemoryStore::new("gateway1".to_string());
         let mut stats = GatewayLatencyStats {
             gateway_id: "gateway1".to_string(),
+            address: "127.0.0.1:8080".to_string(),
             stats: HashMap::new(),
         };
         stats.stats.insert(
@@ -284,7 +467,10 @@ mod tests {
             },
         );
 
-        store.update_gateway_to_service_stats(stats);
+        store
+            .update_gateway_to_service_stats(stats, SystemTime::now())
+            .await
+            .unwrap();
 
         let gateway_to_service = store.gateway_to_service.read().unwrap();
         assert!(gateway_to_service.contains_key("gateway1"));
@@ -295,14 +481,18 @@ mod tests {
         );
     }
 
-    #[test]
-    fn test_update_gateway_to_gateway_stats() {
-        let store = InMemoryStore::new();
-        store.update_gateway_to_gateway_stats(
-            "gateway1".to_string(),
-            "gateway2".to_string(),
-            Duration::from_secs(2),
-        );
+    #[tokio::test]
+    async fn test_update_gateway_to_gateway_stats() {
+        let store = InMemoryStore::new("gateway1".to_string());
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway1".to_string(),
+                "gateway2".to_string(),
+                Duration::from_secs(2),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
 
         let gateway_to_gateway = store.gateway_to_gateway.read().unwrap();
         assert!(gateway_to_gateway.contains_key("gateway1"));
@@ -313,67 +503,469 @@ mod tests {
         );
     }
 
-    #[test]
-    fn test_get_optimal_service_path() {
-        let store = InMemoryStore::new();
-        let mut optimal_paths = store.optimal_paths.write().unwrap();
-        optimal_paths.insert(
+    #[tokio::test]
+    async fn test_get_optimal_service_path() {
+        let store = InMemoryStore::new("gateway1".to_string());
+        store.optimal_paths.write().unwrap().insert(
             "service1".to_string(),
             OptimalPath {
-                gateway: "gateway1".to_string(),
-                latency: Duration::from_secs(3),
+                path: ServicePath {
+                    hops: vec!["gateway1".to_string()],
+                    latency: Duration::from_secs(3),
+                },
                 last_updated: SystemTime::now(),
             },
         );
-        drop(optimal_paths);
 
-        let result = store.get_optimal_service_path("service1");
+        let result = store.get_optimal_service_path("service1").await.unwrap();
         assert!(result.is_some());
-        let (gateway, latency) = result.unwrap();
-        assert_eq!(gateway, "gateway1".to_string());
-        assert_eq!(latency, Duration::from_secs(3));
+        let path = result.unwrap();
+        assert_eq!(path.gateway(), "gateway1");
+        assert_eq!(path.latency, Duration::from_secs(3));
     }
 
-    #[test]
-    fn test_get_gateway_to_service_stats() {
-        let store = InMemoryStore::new();
-        let mut gateway_to_service = store.gateway_to_service.write().unwrap();
+    #[tokio::test]
+    async fn test_get_gateway_to_service_stats() {
+        let store = InMemoryStore::new("gateway1".to_string());
         let mut services = HashMap::new();
         services.insert(
             "service1".to_string(),
             GatewayToServiceStats {
                 service_id: "service1".to_string(),
                 latency: Duration::from_secs(1),
+                status: ServiceStatus::Up,
+                error: None,
                 last_updated: SystemTime::now(),
+                reported_at: SystemTime::now(),
             },
         );
-        gateway_to_service.insert("gateway1".to_string(), services);
-        drop(gateway_to_service);
+        store
+            .gateway_to_service
+            .write()
+            .unwrap()
+            .insert("gateway1".to_string(), services);
 
-        let result = store.get_gateway_to_service_stats("gateway1", "service1");
+        let result = store
+            .get_gateway_to_service_stats("gateway1", "service1")
+            .await
+            .unwrap();
         assert!(result.is_some());
         let stats = result.unwrap();
         assert_eq!(stats.latency, Duration::from_secs(1));
     }
 
-    #[test]
-    fn test_get_gateway_to_gateway_stats() {
-        let store = InMemoryStore::new();
-        let mut gateway_to_gateway = store.gateway_to_gateway.write().unwrap();
+    #[tokio::test]
+    async fn test_get_gateway_to_gateway_stats() {
+        let store = InMemoryStore::new("gateway1".to_string());
         let mut gateways = HashMap::new();
         gateways.insert(
             "gateway2".to_string(),
             GatewayToGatewayStats {
                 latency: Duration::from_secs(2),
                 last_updated: SystemTime::now(),
+                reported_at: SystemTime::now(),
             },
         );
-        gateway_to_gateway.insert("gateway1".to_string(), gateways);
-        drop(gateway_to_gateway);
+        store
+            .gateway_to_gateway
+            .write()
+            .unwrap()
+            .insert("gateway1".to_string(), gateways);
 
-        let result = store.get_gateway_to_gateway_stats("gateway1", "gateway2");
+        let result = store
+            .get_gateway_to_gateway_stats("gateway1", "gateway2")
+            .await
+            .unwrap();
         assert!(result.is_some());
         let stats = result.unwrap();
         assert_eq!(stats.latency, Duration::from_secs(2));
     }
+
+    #[tokio::test]
+    async fn test_remove_gateway() {
+        let store = InMemoryStore::new("gateway2".to_string());
+        for (gateway_id, latency) in [("gateway1", 1), ("gateway2", 2)] {
+            let mut stats =
+                GatewayLatencyStats::new(gateway_id.to_string(), "127.0.0.1:8080".to_string());
+            stats.stats.insert(
+                "service1".to_string(),
+                crate::common::types::ServiceStat {
+                    latency: Duration::from_secs(latency),
+                    service_id: "service1".to_string(),
+                    status: ServiceStatus::Up,
+                    error: None,
+                },
+            );
+            store
+                .update_gateway_to_service_stats(stats, SystemTime::now())
+                .await
+                .unwrap();
+        }
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway2".to_string(),
+                "gateway1".to_string(),
+                Duration::from_millis(500),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .get_optimal_service_path("service1")
+                .await
+                .unwrap()
+                .unwrap()
+                .gateway(),
+            "gateway1"
+        );
+
+        store.remove_gateway("gateway1").await.unwrap();
+        assert!(store
+            .get_gateway_to_service_stats("gateway1", "service1")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_gateway_to_gateway_stats("gateway2", "gateway1")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            store
+                .get_optimal_service_path("service1")
+                .await
+                .unwrap()
+                .unwrap()
+                .gateway(),
+            "gateway2"
+        );
+
+        store.remove_gateway("gateway2").await.unwrap();
+        assert!(store
+            .get_optimal_service_path("service1")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    fn service_stats(
+        gateway_id: &str,
+        latency: Duration,
+        status: ServiceStatus,
+    ) -> GatewayLatencyStats {
+        let mut stats =
+            GatewayLatencyStats::new(gateway_id.to_string(), "127.0.0.1:8080".to_string());
+        stats.stats.insert(
+            "service1".to_string(),
+            crate::common::types::ServiceStat {
+                latency,
+                service_id: "service1".to_string(),
+                error: (status == ServiceStatus::Down).then(|| "connection refused".to_string()),
+                status,
+            },
+        );
+        stats
+    }
+
+    #[tokio::test]
+    async fn test_down_service_is_excluded() {
+        let store = InMemoryStore::new("gateway1".to_string());
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway1".to_string(),
+                "gateway2".to_string(),
+                Duration::ZERO,
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(
+                service_stats("gateway1", Duration::from_millis(10), ServiceStatus::Up),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(
+                service_stats("gateway2", Duration::from_millis(20), ServiceStatus::Up),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .get_optimal_service_path("service1")
+                .await
+                .unwrap()
+                .unwrap()
+                .gateway(),
+            "gateway1"
+        );
+
+        // a down service reports 0ms and must not win
+        store
+            .update_gateway_to_service_stats(
+                service_stats("gateway1", Duration::from_millis(0), ServiceStatus::Down),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        let stats = store
+            .get_gateway_to_service_stats("gateway1", "service1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.status, ServiceStatus::Down);
+        assert_eq!(stats.error.as_deref(), Some("connection refused"));
+        let path = ServicePath {
+            hops: vec!["gateway1".to_string(), "gateway2".to_string()],
+            latency: Duration::from_millis(20),
+        };
+        assert_eq!(
+            store.get_optimal_service_path("service1").await.unwrap(),
+            Some(path.clone())
+        );
+        assert_eq!(
+            store.get_service_paths("service1").await.unwrap(),
+            vec![path]
+        );
+
+        store
+            .update_gateway_to_service_stats(
+                service_stats("gateway2", Duration::from_millis(0), ServiceStatus::Down),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        assert!(store
+            .get_optimal_service_path("service1")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_service_paths("service1")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remove_gateway_to_gateway_stats() {
+        let store = InMemoryStore::new("gateway1".to_string());
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway1".to_string(),
+                "gateway2".to_string(),
+                Duration::from_secs(2),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        store
+            .remove_gateway_to_gateway_stats("gateway1", "gateway2", SystemTime::now())
+            .await
+            .unwrap();
+        assert!(store
+            .get_gateway_to_gateway_stats("gateway1", "gateway2")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_older_stats_are_discarded() {
+        let store = InMemoryStore::new("gateway1".to_string());
+        let now = SystemTime::now();
+        let earlier = now - Duration::from_secs(5);
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway1".to_string(),
+                "gateway2".to_string(),
+                Duration::from_secs(2),
+                now,
+            )
+            .await
+            .unwrap();
+
+        // reordered in transit, neither may undo the newer measurement
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway1".to_string(),
+                "gateway2".to_string(),
+                Duration::from_secs(9),
+                earlier,
+            )
+            .await
+            .unwrap();
+        store
+            .remove_gateway_to_gateway_stats("gateway1", "gateway2", earlier)
+            .await
+            .unwrap();
+
+        let stats = store
+            .get_gateway_to_gateway_stats("gateway1", "gateway2")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.latency, Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_expired_stats_are_ignored_and_evicted() {
+        let store = InMemoryStore::with_config(
+            "gateway2".to_string(),
+            &StoreConfig {
+                service_stats_max_age: Duration::from_secs(30),
+                gateway_stats_max_age: Duration::from_secs(30),
+                sweep_interval: Duration::from_secs(5),
+                ..StoreConfig::default()
+            },
+        );
+        store
+            .update_gateway_to_service_stats(
+                service_stats("gateway1", Duration::from_millis(10), ServiceStatus::Up),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_service_stats(
+                service_stats("gateway2", Duration::from_millis(20), ServiceStatus::Up),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway2".to_string(),
+                "gateway1".to_string(),
+                Duration::from_millis(5),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .get_optimal_service_path("service1")
+                .await
+                .unwrap()
+                .unwrap()
+                .gateway(),
+            "gateway1"
+        );
+
+        // gateway1 went silent a minute ago
+        let stale = SystemTime::now() - Duration::from_secs(60);
+        store
+            .gateway_to_service
+            .write()
+            .unwrap()
+            .get_mut("gateway1")
+            .unwrap()
+            .get_mut("service1")
+            .unwrap()
+            .last_updated = stale;
+        store
+            .gateway_to_gateway
+            .write()
+            .unwrap()
+            .get_mut("gateway2")
+            .unwrap()
+            .get_mut("gateway1")
+            .unwrap()
+            .last_updated = stale;
+
+        // lookups honor the expiry before the sweeper runs
+        assert!(store
+            .get_gateway_to_service_stats("gateway1", "service1")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_gateway_to_gateway_stats("gateway2", "gateway1")
+            .await
+            .unwrap()
+            .is_none());
+        let path = ServicePath {
+            hops: vec!["gateway2".to_string()],
+            latency: Duration::from_millis(20),
+        };
+        assert_eq!(
+            store.get_service_paths("service1").await.unwrap(),
+            vec![path.clone()]
+        );
+        assert_eq!(
+            store.get_optimal_service_path("service1").await.unwrap(),
+            Some(path)
+        );
+
+        store.evict_expired().await.unwrap();
+        assert!(!store
+            .gateway_to_service
+            .read()
+            .unwrap()
+            .contains_key("gateway1"));
+        assert!(store.gateway_to_gateway.read().unwrap().is_empty());
+        assert_eq!(
+            store.optimal_paths.read().unwrap()["service1"]
+                .path
+                .gateway(),
+            "gateway2"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_service_becomes_reachable_with_mesh() {
+        let store = InMemoryStore::new("gateway1".to_string());
+        store
+            .update_gateway_to_service_stats(
+                service_stats("gateway3", Duration::from_millis(10), ServiceStatus::Up),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        assert!(store
+            .get_optimal_service_path("service1")
+            .await
+            .unwrap()
+            .is_none());
+
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway1".to_string(),
+                "gateway2".to_string(),
+                Duration::from_millis(5),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway2".to_string(),
+                "gateway3".to_string(),
+                Duration::from_millis(5),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        let path = store
+            .get_optimal_service_path("service1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(path.hops, vec!["gateway1", "gateway2", "gateway3"]);
+        assert_eq!(path.latency, Duration::from_millis(20));
+
+        store
+            .remove_gateway_to_gateway_stats("gateway2", "gateway3", SystemTime::now())
+            .await
+            .unwrap();
+        assert!(store
+            .get_optimal_service_path("service1")
+            .await
+            .unwrap()
+            .is_none());
+    }
 }
@@ -1,2 +1,5 @@
 pub mod memory;
+pub mod path;
+pub mod redis;
+#[allow(clippy::module_inception)]
 pub mod store;
@@ -0,0 +1,120 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    time::Duration,
+};
+
+use super::store::ServicePath;
+
+// Ranks every gateway that can serve a service by the shortest path from
+// `root`. `mesh` holds the latency from a gateway to each gateway it probed,
+// `services` the latency from a gateway to the service. Gateways the root
+// can't reach through the mesh are left out.
+pub fn shortest_service_paths(
+    root: &str,
+    mesh: &HashMap<String, HashMap<String, Duration>>,
+    services: &HashMap<String, Duration>,
+) -> Vec<ServicePath> {
+    let mut distances: HashMap<&str, Duration> = HashMap::from([(root, Duration::ZERO)]);
+    let mut previous: HashMap<&str, &str> = HashMap::new();
+    let mut queue = BinaryHeap::from([Reverse((Duration::ZERO, root))]);
+
+    while let Some(Reverse((distance, gateway))) = queue.pop() {
+        if distances.get(gateway).is_some_and(|best| distance > *best) {
+            continue;
+        }
+        let Some(neighbours) = mesh.get(gateway) else {
+            continue;
+        };
+        for (neighbour, latency) in neighbours {
+            let candidate = distance.saturating_add(*latency);
+            if distances
+                .get(neighbour.as_str())
+                .is_none_or(|best| candidate < *best)
+            {
+                distances.insert(neighbour, candidate);
+                previous.insert(neighbour, gateway);
+                queue.push(Reverse((candidate, neighbour)));
+            }
+        }
+    }
+
+    let mut paths: Vec<ServicePath> = services
+        .iter()
+        .filter_map(|(gateway, service_latency)| {
+            let distance = distances.get(gateway.as_str())?;
+            let mut hops = vec![gateway.clone()];
+            let mut current = gateway.as_str();
+            while let Some(hop) = previous.get(current) {
+                hops.push(hop.to_string());
+                current = hop;
+            }
+            hops.reverse();
+            Some(ServicePath {
+                hops,
+                latency: distance.saturating_add(*service_latency),
+            })
+        })
+        .collect();
+    paths.sort_by(|a, b| {
+        a.latency
+            .cmp(&b.latency)
+            .then_with(|| a.gateway().cmp(b.gateway()))
+    });
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(edges: &[(&str, &str, u64)]) -> HashMap<String, HashMap<String, Duration>> {
+        let mut mesh: HashMap<String, HashMap<String, Duration>> = HashMap::new();
+        for (from, to, latency) in edges {
+            mesh.entry(from.to_string())
+                .or_default()
+                .insert(to.to_string(), Duration::from_millis(*latency));
+        }
+        mesh
+    }
+
+    fn hops(hops: &[&str]) -> Vec<String> {
+        hops.iter().map(|hop| hop.to_string()).collect()
+    }
+
+    #[test]
+    fn test_shortest_service_paths() {
+        // gateway1 reaches gateway4 faster through gateway2 and gateway3
+        // than directly, gateway5 isn't reachable from gateway1 at all
+        let mesh = mesh(&[
+            ("gateway1", "gateway2", 10),
+            ("gateway1", "gateway4", 100),
+            ("gateway2", "gateway3", 10),
+            ("gateway3", "gateway4", 10),
+            ("gateway5", "gateway4", 1),
+        ]);
+        let services = HashMap::from([
+            ("gateway1".to_string(), Duration::from_millis(80)),
+            ("gateway4".to_string(), Duration::from_millis(5)),
+            ("gateway5".to_string(), Duration::from_millis(1)),
+        ]);
+
+        let paths = shortest_service_paths("gateway1", &mesh, &services);
+        assert_eq!(
+            paths,
+            vec![
+                ServicePath {
+                    hops: hops(&["gateway1", "gateway2", "gateway3", "gateway4"]),
+                    latency: Duration::from_millis(35),
+                },
+                ServicePath {
+                    hops: hops(&["gateway1"]),
+                    latency: Duration::from_millis(80),
+                },
+            ]
+        );
+        assert_eq!(paths[0].gateway(), "gateway4");
+        assert_eq!(paths[0].next_hop(), "gateway2");
+        assert_eq!(paths[1].next_hop(), "gateway1");
+    }
+}
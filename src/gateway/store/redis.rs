@@ -1,69 +1,197 @@
-use crate::{common::types::GatewayLatencyStats, gateway::config::ServiceConfig};
-use redis::aio::ConnectionManager;
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
-use tokio::sync::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, AsyncCommands, Script};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::{debug, info, trace, warn};
 
+use crate::common::types::{GatewayLatencyStats, ServiceStatus};
+use crate::gateway::config::{RedisStoreConfig, StoreConfig};
+
 use super::{
-    store::GatewayToGatewayStats, store::GatewayToServiceStats, store::OptimalPath, store::Store,
+    path::shortest_service_paths, store::GatewayToGatewayStats, store::GatewayToServiceStats,
+    store::OptimalPath, store::ServicePath, store::Store,
 };
 
-#[derive(Debug)]
+// Keys, all under the configured prefix:
+//   services                      set of every service id ever reported
+//   gateways                      set of every gateway id ever reported
+//   service:{service id}          hash of gateway id -> GatewayToServiceStats
+//   mesh:{gateway id}             hash of gateway id -> GatewayToGatewayStats
+//   optimal_paths:{gateway id}    hash of service id -> CachedPath, the
+//                                 paths are rooted at that gateway
+//   service_paths:{gateway id}    hash of service id -> CachedPaths, every
+//                                 path from that gateway, best first
+//   generation                    bumped by every write to the stats, by
+//                                 any gateway
+// so every lookup is a single hash access and nothing needs KEYS or SCAN
 pub struct RedisStore {
-    client: redis::Client,
-    connection_manager: Mutex<ConnectionManager>,
+    // paths are calculated from this gateway
+    local_gateway: String,
+    key_prefix: String,
+    connection: ConnectionManager,
+    service_stats_max_age: Duration,
+    gateway_stats_max_age: Duration,
 }
 
-impl Store for RedisStore {
-    async fn new() -> Self {
-        info!("creating new redis store");
-        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
-        let connection_manager = Mutex::new(client.get_tokio_connection_manager().await.unwrap());
-        RedisStore {
-            client,
-            connection_manager,
-        }
+// a gateway's best path to a service, computed from the stats as of
+// `generation`. Other gateways write the shared stats without touching this
+// gateway's cache, so the path is stale once the generation moved on
+#[derive(Serialize, Deserialize)]
+struct CachedPath {
+    #[serde(default)]
+    generation: u64,
+    #[serde(flatten)]
+    optimal_path: OptimalPath,
+}
+
+// every path of a gateway to a service as of `generation`. Stats expire
+// without a write, so the paths only hold until the first stat they were
+// computed from expires, None when none did
+#[derive(Serialize, Deserialize)]
+struct CachedPaths {
+    generation: u64,
+    valid_until: Option<SystemTime>,
+    paths: Vec<ServicePath>,
+}
+
+// KEYS: the generation counter, then the hashes. ARGV: the field, the
+// seconds and nanoseconds of when the stats were reported, then a value per
+// hash, empty to remove the field. Bumps the generation if anything changed
+static STORE_IF_NEWER: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local secs, nanos = tonumber(ARGV[2]), tonumber(ARGV[3])
+        local changed, any = {}, false
+        for i = 2, #KEYS do
+            local value = ARGV[i + 2]
+            local held = redis.call('HGET', KEYS[i], ARGV[1])
+            local newer = false
+            -- stats stored before they carried reported_at are older
+            local at = held and cjson.decode(held).reported_at
+            if at then
+                newer = at.secs_since_epoch > secs
+                    or (at.secs_since_epoch == secs and at.nanos_since_epoch > nanos)
+            end
+            changed[i - 1] = 0
+            if not newer and value ~= '' then
+                redis.call('HSET', KEYS[i], ARGV[1], value)
+                changed[i - 1] = 1
+            elseif not newer and held then
+                redis.call('HDEL', KEYS[i], ARGV[1])
+                changed[i - 1] = 1
+            end
+            any = any or changed[i - 1] == 1
+        end
+        if any then
+            redis.call('INCR', KEYS[1])
+        end
+        return changed
+        ",
+    )
+});
+
+// KEYS: the generation counter, then the hashes. ARGV: the seconds and
+// nanoseconds stats updated before are expired. Removes the expired fields
+// as they are when checked, so a fresher stat written meanwhile stays.
+// Returns the hash and field of each removed stat, bumps the generation if
+// there were any
+static EVICT_EXPIRED: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local secs, nanos = tonumber(ARGV[1]), tonumber(ARGV[2])
+        local evicted = {}
+        for i = 2, #KEYS do
+            local fields = redis.call('HGETALL', KEYS[i])
+            for j = 1, #fields, 2 do
+                local at = cjson.decode(fields[j + 1]).last_updated
+                if not at or at.secs_since_epoch < secs
+                    or (at.secs_since_epoch == secs and at.nanos_since_epoch < nanos) then
+                    redis.call('HDEL', KEYS[i], fields[j])
+                    table.insert(evicted, KEYS[i])
+                    table.insert(evicted, fields[j])
+                end
+            end
+        end
+        if #evicted > 0 then
+            redis.call('INCR', KEYS[1])
+        end
+        return evicted
+        ",
+    )
+});
+
+impl std::fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("local_gateway", &self.local_gateway)
+            .field("key_prefix", &self.key_prefix)
+            .finish()
     }
+}
 
+#[async_trait]
+impl Store for RedisStore {
     async fn update_gateway_to_service_stats(
         &self,
         stats: GatewayLatencyStats,
-        service_configs: &HashMap<String, ServiceConfig>,
-    ) {
+        reported_at: SystemTime,
+    ) -> Result<()> {
         trace!(
             "updating gateway-to-service stats for gateway: {}",
             stats.gateway_id
         );
-        let mut con = self.connection_manager.lock().await;
-        let affected_services: Vec<String> = stats.stats.keys().cloned().collect();
-
-        for (service_id, service_stat) in stats.stats {
-            if let Some(service_config) = service_configs.get(&service_id) {
-                let key = format!("gateway_to_service:{}:{}", stats.gateway_id, service_id);
-                let value = GatewayToServiceStats {
-                    latency: service_stat.latency,
-                    last_updated: SystemTime::now(),
-                    service_config: service_config.clone(),
-                };
-                let _: () = con
-                    .set_ex(key, serde_json::to_string(&value).unwrap(), 3600) // Set TTL for 1 hour
-                    .await
-                    .unwrap();
-                trace!(
-                    "updated stats for service: {} with latency: {:?}",
-                    service_id,
-                    service_stat.latency
+        let mut pipe = redis::pipe();
+        pipe.sadd(self.key("gateways"), &stats.gateway_id).ignore();
+        // in the order the script reports back what changed
+        let service_stats: Vec<_> = stats.stats.into_iter().collect();
+        let mut writes = Vec::new();
+        for (service_id, service_stat) in &service_stats {
+            let value = GatewayToServiceStats {
+                service_id: service_id.clone(),
+                latency: service_stat.latency,
+                status: service_stat.status,
+                error: service_stat.error.clone(),
+                last_updated: SystemTime::now(),
+                reported_at,
+            };
+            pipe.sadd(self.key("services"), service_id).ignore();
+            writes.push((self.service_key(service_id), Some(encode(&value)?)));
+        }
+        pipe.query_async::<()>(&mut self.connection())
+            .await
+            .context("Failed to update gateway-to-service stats")?;
+        let changed = self
+            .store_if_newer(&stats.gateway_id, reported_at, &writes)
+            .await
+            .context("Failed to update gateway-to-service stats")?;
+
+        let mut affected_services = Vec::new();
+        for ((service_id, service_stat), changed) in service_stats.into_iter().zip(changed) {
+            if !changed {
+                debug!(
+                    "discarding outdated stats for service {} from gateway {}",
+                    service_id, stats.gateway_id
                 );
-            } else {
-                warn!("service config not found for service: {}", service_id);
+                continue;
             }
+            info!(
+                "updated stats for service: {} with status: {:?}, latency: {:?}",
+                service_id, service_stat.status, service_stat.latency
+            );
+            affected_services.push(service_id);
         }
 
         // Update optimal paths for all affected services
         for service_id in affected_services {
-            self.update_optimal_path(&service_id).await;
+            self.update_paths(&service_id).await?;
         }
+        Ok(())
     }
 
     async fn update_gateway_to_gateway_stats(
@@ -71,22 +199,37 @@ impl Store for RedisStore {
         from_gateway: String,
         to_gateway: String,
         latency: Duration,
-    ) {
+        reported_at: SystemTime,
+    ) -> Result<()> {
         trace!(
             "updating gateway-to-gateway stats: {} -> {}",
             from_gateway,
             to_gateway
         );
-        let mut con = self.connection_manager.lock().await;
-        let key = format!("gateway_to_gateway:{}:{}", from_gateway, to_gateway);
         let value = GatewayToGatewayStats {
             latency,
             last_updated: SystemTime::now(),
+            reported_at,
         };
-        let _: () = con
-            .set_ex(key, serde_json::to_string(&value).unwrap(), 3600) // Set TTL for 1 hour
+        self.connection()
+            .sadd::<_, _, ()>(self.key("gateways"), &from_gateway)
             .await
-            .unwrap();
+            .context("Failed to update gateway-to-gateway stats")?;
+        let changed = self
+            .store_if_newer(
+                &to_gateway,
+                reported_at,
+                &[(self.mesh_key(&from_gateway), Some(encode(&value)?))],
+            )
+            .await
+            .context("Failed to update gateway-to-gateway stats")?;
+        if changed != [true] {
+            debug!(
+                "discarding outdated gateway-to-gateway stats: {} -> {}",
+                from_gateway, to_gateway
+            );
+            return Ok(());
+        }
 
         debug!(
             "updated gateway-to-gateway stats: {} -> {} with latency: {:?}",
@@ -94,168 +237,687 @@ impl Store for RedisStore {
         );
 
         // Update optimal paths for all services
-        let mut cursor = 0;
-        loop {
-            let (next_cursor, service_ids): (u64, Vec<String>) =
-                con.scan_match("optimal_path:*").await.unwrap();
-            cursor = next_cursor;
-            for service_id in service_ids {
-                self.update_optimal_path(&service_id).await;
-            }
-            if cursor == 0 {
-                break;
-            }
-        }
+        self.update_all_paths().await
     }
 
-    async fn get_optimal_service_path(&self, service_id: &str) -> Option<(String, Duration)> {
-        trace!("getting optimal service path for service: {}", service_id);
-        let mut con = self.connection_manager.lock().await;
-        let key = format!("optimal_path:{}", service_id);
-        let value: String = con.get(key).await.ok()?;
-        let optimal_path: OptimalPath = serde_json::from_str(&value).unwrap();
+    async fn remove_gateway_to_gateway_stats(
+        &self,
+        from_gateway: &str,
+        to_gateway: &str,
+        reported_at: SystemTime,
+    ) -> Result<()> {
         trace!(
-            "found optimal path for service: {}. gateway: {}, latency: {:?}",
-            service_id,
-            optimal_path.gateway,
-            optimal_path.latency
+            "removing gateway-to-gateway stats: {} -> {}",
+            from_gateway,
+            to_gateway
+        );
+        // a stat reported after the removal stays
+        let changed = self
+            .store_if_newer(
+                to_gateway,
+                reported_at,
+                &[(self.mesh_key(from_gateway), None)],
+            )
+            .await
+            .context("Failed to remove gateway-to-gateway stats")?;
+        if changed != [true] {
+            return Ok(());
+        }
+
+        debug!(
+            "removed gateway-to-gateway stats: {} -> {}",
+            from_gateway, to_gateway
+        );
+
+        // Update optimal paths for all services
+        self.update_all_paths().await
+    }
+
+    async fn remove_gateway(&self, gateway_id: &str) -> Result<()> {
+        trace!("removing gateway: {}", gateway_id);
+        let mut con = self.connection();
+        let service_ids: HashSet<String> = con
+            .smembers(self.key("services"))
+            .await
+            .context("Failed to get services")?;
+        let gateway_ids: HashSet<String> = con
+            .smembers(self.key("gateways"))
+            .await
+            .context("Failed to get gateways")?;
+
+        let mut pipe = redis::pipe();
+        for service_id in &service_ids {
+            pipe.hdel(self.service_key(service_id), gateway_id).ignore();
+        }
+        for from_gateway in &gateway_ids {
+            pipe.hdel(self.mesh_key(from_gateway), gateway_id).ignore();
+        }
+        pipe.del(self.mesh_key(gateway_id))
+            .ignore()
+            .srem(self.key("gateways"), gateway_id)
+            .ignore()
+            .incr(self.key("generation"), 1)
+            .ignore()
+            .query_async::<()>(&mut con)
+            .await
+            .context("Failed to remove gateway")?;
+
+        info!("removed gateway: {}", gateway_id);
+
+        // Update optimal paths for all services
+        self.update_all_paths().await
+    }
+
+    async fn evict_expired(&self) -> Result<()> {
+        trace!("evicting expired stats");
+        let mut con = self.connection();
+        let service_ids: HashSet<String> = con
+            .smembers(self.key("services"))
+            .await
+            .context("Failed to get services")?;
+        let gateway_ids: HashSet<String> = con
+            .smembers(self.key("gateways"))
+            .await
+            .context("Failed to get gateways")?;
+
+        let service_keys: Vec<_> = service_ids.iter().map(|id| self.service_key(id)).collect();
+        let mesh_keys: Vec<_> = gateway_ids.iter().map(|id| self.mesh_key(id)).collect();
+        let mut evicted = self
+            .evict_updated_before(&service_keys, self.service_stats_max_age)
+            .await?;
+        evicted.extend(
+            self.evict_updated_before(&mesh_keys, self.gateway_stats_max_age)
+                .await?,
         );
-        Some((optimal_path.gateway, optimal_path.latency))
+        if evicted.is_empty() {
+            return Ok(());
+        }
+        for (key, field) in &evicted {
+            debug!("evicted expired stats of {} from {}", field, key);
+        }
+        self.update_all_paths().await
+    }
+
+    async fn get_optimal_service_path(&self, service_id: &str) -> Result<Option<ServicePath>> {
+        trace!("getting optimal service path for service: {}", service_id);
+        let (generation, cached): (Option<u64>, Option<String>) = redis::pipe()
+            .get(self.key("generation"))
+            .hget(self.optimal_paths_key(), service_id)
+            .query_async(&mut self.connection())
+            .await
+            .context("Failed to get optimal path")?;
+        let cached = cached.as_deref().map(decode::<CachedPath>).transpose()?;
+
+        if let Some(cached) = cached {
+            if cached.generation == generation.unwrap_or_default() {
+                // don't hand out a path the sweeper hasn't caught up with yet
+                let expired = self
+                    .hget::<GatewayToServiceStats>(
+                        &self.service_key(service_id),
+                        cached.optimal_path.path.gateway(),
+                    )
+                    .await?
+                    .is_none_or(|stats| stats.is_expired(self.service_stats_max_age));
+                if !expired {
+                    return Ok(Some(cached.optimal_path.path));
+                }
+            }
+        }
+        Ok(self.update_paths(service_id).await?.into_iter().next())
+    }
+
+    // looked up for every routed request, so served from the cache as long
+    // as nothing changed since the paths were computed
+    async fn get_service_paths(&self, service_id: &str) -> Result<Vec<ServicePath>> {
+        trace!("getting service paths for service: {}", service_id);
+        let (generation, cached): (Option<u64>, Option<String>) = redis::pipe()
+            .get(self.key("generation"))
+            .hget(self.service_paths_key(), service_id)
+            .query_async(&mut self.connection())
+            .await
+            .context("Failed to get service paths")?;
+        let cached = cached.as_deref().map(decode::<CachedPaths>).transpose()?;
+
+        if let Some(cached) = cached {
+            let valid = cached
+                .valid_until
+                .is_none_or(|valid_until| SystemTime::now() < valid_until);
+            if cached.generation == generation.unwrap_or_default() && valid {
+                return Ok(cached.paths);
+            }
+        }
+        self.update_paths(service_id).await
     }
 
     async fn get_gateway_to_service_stats(
         &self,
         gateway_id: &str,
         service_id: &str,
-    ) -> Option<GatewayToServiceStats> {
+    ) -> Result<Option<GatewayToServiceStats>> {
         trace!(
             "getting gateway-to-service stats for gateway: {}, service: {}",
             gateway_id,
             service_id
         );
-        let mut con = self.connection_manager.lock().await;
-        let key = format!("gateway_to_service:{}:{}", gateway_id, service_id);
-        let value: String = con.get(key).await.ok()?;
-        let stats: GatewayToServiceStats = serde_json::from_str(&value).unwrap();
-        trace!(
-            "found gateway-to-service stats. latency: {:?}",
-            stats.latency
-        );
-        Some(stats)
+        Ok(self
+            .hget::<GatewayToServiceStats>(&self.service_key(service_id), gateway_id)
+            .await?
+            .filter(|stats| !stats.is_expired(self.service_stats_max_age)))
     }
 
     async fn get_gateway_to_gateway_stats(
         &self,
         from_gateway: &str,
         to_gateway: &str,
-    ) -> Option<GatewayToGatewayStats> {
+    ) -> Result<Option<GatewayToGatewayStats>> {
         trace!(
             "getting gateway-to-gateway stats for: {} -> {}",
             from_gateway,
             to_gateway
         );
-        let mut con = self.connection_manager.lock().await;
-        let key = format!("gateway_to_gateway:{}:{}", from_gateway, to_gateway);
-        let value: String = con.get(key).await.ok()?;
-        let stats: GatewayToGatewayStats = serde_json::from_str(&value).unwrap();
-        trace!(
-            "found gateway-to-gateway stats. latency: {:?}",
-            stats.latency
-        );
-        Some(stats)
+        Ok(self
+            .hget::<GatewayToGatewayStats>(&self.mesh_key(from_gateway), to_gateway)
+            .await?
+            .filter(|stats| !stats.is_expired(self.gateway_stats_max_age)))
     }
 }
 
 impl RedisStore {
-    async fn update_optimal_path(&self, service_id: &str) {
-        trace!("updating optimal path for service: {}", service_id);
-        if let Some((gateway, latency)) = self.calculate_optimal_service_path(service_id).await {
-            let mut con = self.connection_manager.lock().await;
-            let key = format!("optimal_path:{}", service_id);
-            let value = OptimalPath {
-                gateway: gateway.clone(),
-                latency,
-                last_updated: SystemTime::now(),
-            };
-            let _: () = con
-                .set_ex(key, serde_json::to_string(&value).unwrap(), 3600) // Set TTL for 1 hour
-                .await
-                .unwrap();
+    pub async fn new(
+        local_gateway: String,
+        redis: &RedisStoreConfig,
+        conf: &StoreConfig,
+    ) -> Result<Self> {
+        info!("creating new redis store at {}", redis.url);
+        let client = redis::Client::open(redis.url.as_str()).context("Invalid redis url")?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .context("Failed to connect to redis")?;
+        Ok(RedisStore {
+            local_gateway,
+            key_prefix: redis.key_prefix.clone(),
+            connection,
+            service_stats_max_age: conf.service_stats_max_age,
+            gateway_stats_max_age: conf.gateway_stats_max_age,
+        })
+    }
+
+    // the connection manager multiplexes a single connection and is cheap to
+    // clone, every command takes its own handle
+    fn connection(&self) -> ConnectionManager {
+        self.connection.clone()
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}:{}", self.key_prefix, name)
+    }
+
+    fn service_key(&self, service_id: &str) -> String {
+        format!("{}:service:{}", self.key_prefix, service_id)
+    }
+
+    fn mesh_key(&self, gateway_id: &str) -> String {
+        format!("{}:mesh:{}", self.key_prefix, gateway_id)
+    }
+
+    fn optimal_paths_key(&self) -> String {
+        format!("{}:optimal_paths:{}", self.key_prefix, self.local_gateway)
+    }
+
+    fn service_paths_key(&self) -> String {
+        format!("{}:service_paths:{}", self.key_prefix, self.local_gateway)
+    }
+
+    // stores the values under `field` of each hash, or removes the field for
+    // None, unless the stat held was reported after `reported_at`. Checked
+    // and written atomically in a single round trip, so gateways sharing
+    // the keys can't overwrite a newer stat with an older one. Returns
+    // whether each hash changed
+    async fn store_if_newer(
+        &self,
+        field: &str,
+        reported_at: SystemTime,
+        writes: &[(String, Option<String>)],
+    ) -> Result<Vec<bool>> {
+        if writes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let reported_at = reported_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut invocation = STORE_IF_NEWER.key(self.key("generation"));
+        invocation
+            .arg(field)
+            .arg(reported_at.as_secs())
+            .arg(reported_at.subsec_nanos());
+        for (key, value) in writes {
+            invocation
+                .key(key)
+                .arg(value.as_deref().unwrap_or_default());
+        }
+        let changed: Vec<u8> = invocation.invoke_async(&mut self.connection()).await?;
+        Ok(changed.into_iter().map(|changed| changed == 1).collect())
+    }
+
+    // removes the stats in the hashes that are older than `max_age`, checked
+    // and removed atomically. Returns the hash and field of each removed stat
+    async fn evict_updated_before(
+        &self,
+        keys: &[String],
+        max_age: Duration,
+    ) -> Result<Vec<(String, String)>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let cutoff = SystemTime::now()
+            .checked_sub(max_age)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut invocation = EVICT_EXPIRED.key(self.key("generation"));
+        invocation.arg(cutoff.as_secs()).arg(cutoff.subsec_nanos());
+        for key in keys {
+            invocation.key(key);
+        }
+        let evicted: Vec<String> = invocation
+            .invoke_async(&mut self.connection())
+            .await
+            .context("Failed to evict expired stats")?;
+        Ok(evicted
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect())
+    }
+
+    async fn hget<T: DeserializeOwned>(&self, key: &str, field: &str) -> Result<Option<T>> {
+        let value: Option<String> = self
+            .connection()
+            .hget(key, field)
+            .await
+            .with_context(|| format!("Failed to get {} from {}", field, key))?;
+        value.as_deref().map(decode).transpose()
+    }
+
+    async fn hgetall<T: DeserializeOwned>(&self, key: &str) -> Result<HashMap<String, T>> {
+        let values: HashMap<String, String> = self
+            .connection()
+            .hgetall(key)
+            .await
+            .with_context(|| format!("Failed to get {}", key))?;
+        values
+            .into_iter()
+            .map(|(field, value)| Ok((field, decode(&value)?)))
+            .collect()
+    }
+
+    // a mesh change can make any service reachable or unreachable, not only
+    // the ones that already have a path
+    async fn update_all_paths(&self) -> Result<()> {
+        let service_ids: HashSet<String> = self
+            .connection()
+            .smembers(self.key("services"))
+            .await
+            .context("Failed to get services")?;
+        for service_id in service_ids {
+            self.update_paths(&service_id).await?;
+        }
+        Ok(())
+    }
+
+    // recomputes and caches the paths to the service and the best of them,
+    // tagged with the generation read before the stats they are computed
+    // from
+    async fn update_paths(&self, service_id: &str) -> Result<Vec<ServicePath>> {
+        trace!("updating paths for service: {}", service_id);
+        let mut con = self.connection();
+        let generation: Option<u64> = con
+            .get(self.key("generation"))
+            .await
+            .context("Failed to get generation")?;
+        let generation = generation.unwrap_or_default();
+        let (paths, valid_until) = self.calculate_service_paths(service_id).await?;
+
+        let mut pipe = redis::pipe();
+        let cached = CachedPaths {
+            generation,
+            valid_until,
+            paths: paths.clone(),
+        };
+        pipe.hset(self.service_paths_key(), service_id, encode(&cached)?)
+            .ignore();
+        if let Some(path) = paths.first() {
             debug!(
-                "updated optimal path for service: {}. gateway: {}, latency: {:?}",
-                service_id, gateway, latency
+                "updated optimal path for service: {}. hops: {:?}, latency: {:?}",
+                service_id, path.hops, path.latency
             );
+            let value = CachedPath {
+                generation,
+                optimal_path: OptimalPath {
+                    path: path.clone(),
+                    last_updated: SystemTime::now(),
+                },
+            };
+            pipe.hset(self.optimal_paths_key(), service_id, encode(&value)?)
+                .ignore();
         } else {
             warn!(
                 "failed to calculate optimal path for service: {}",
                 service_id
             );
+            pipe.hdel(self.optimal_paths_key(), service_id).ignore();
         }
+        pipe.query_async::<()>(&mut con)
+            .await
+            .context("Failed to update paths")?;
+        Ok(paths)
     }
 
-    async fn calculate_optimal_service_path(&self, service_id: &str) -> Option<(String, Duration)> {
-        trace!(
-            "calculating optimal service path for service: {}",
-            service_id
-        );
-        let mut best_gateway = None;
-        let mut best_latency = Duration::MAX;
+    // shortest paths from the local gateway over the fresh mesh to every
+    // gateway where the service is up, and when the first of the stats they
+    // are computed from expires
+    async fn calculate_service_paths(
+        &self,
+        service_id: &str,
+    ) -> Result<(Vec<ServicePath>, Option<SystemTime>)> {
+        trace!("calculating service paths for service: {}", service_id);
+        let mut valid_until: Option<SystemTime> = None;
+        let mut expires = |last_updated: SystemTime, max_age: Duration| {
+            let expiry = last_updated + max_age;
+            valid_until = Some(valid_until.map_or(expiry, |until| until.min(expiry)));
+        };
 
-        let mut con = self.connection_manager.lock().await;
-        let gateway_to_service_keys: Vec<String> = con
-            .scan_match(format!("gateway_to_service:*:{}", service_id))
-            .await
-            .unwrap();
-        let gateway_to_gateway_keys: Vec<String> =
-            con.scan_match("gateway_to_gateway:*").await.unwrap();
-
-        // Check all gateways for direct connections
-        for key in gateway_to_service_keys {
-            let value: String = con.get(&key).await.unwrap();
-            let service_stats: GatewayToServiceStats = serde_json::from_str(&value).unwrap();
-            let gateway_id = key.split(':').nth(1).unwrap().to_string();
-            if service_stats.latency < best_latency {
-                best_gateway = Some(gateway_id.clone());
-                best_latency = service_stats.latency;
-                trace!(
-                    "found better direct path through gateway: {}. latency: {:?}",
-                    gateway_id,
-                    best_latency
-                );
+        let mut services = HashMap::new();
+        for (gateway_id, stats) in self
+            .hgetall::<GatewayToServiceStats>(&self.service_key(service_id))
+            .await?
+        {
+            if stats.status == ServiceStatus::Up && !stats.is_expired(self.service_stats_max_age) {
+                expires(stats.last_updated, self.service_stats_max_age);
+                services.insert(gateway_id, stats.latency);
             }
         }
+        if services.is_empty() {
+            return Ok((Vec::new(), None));
+        }
 
-        // Check paths through other gateways
-        for key in gateway_to_gateway_keys {
-            let value: String = con.get(&key).await.unwrap();
-            let gateway_to_gateway_stats: GatewayToGatewayStats =
-                serde_json::from_str(&value).unwrap();
-            let from_gateway = key.split(':').nth(1).unwrap().to_string();
-            let intermediate_gateway = key.split(':').nth(2).unwrap().to_string();
-            let service_key = format!("gateway_to_service:{}:{}", intermediate_gateway, service_id);
-            if let Ok(service_value) = con.get::<_, String>(&service_key).await {
-                let service_stats: GatewayToServiceStats =
-                    serde_json::from_str(&service_value).unwrap();
-                let total_latency = gateway_to_gateway_stats.latency + service_stats.latency;
-                if total_latency < best_latency {
-                    best_latency = total_latency;
-                    best_gateway = Some(intermediate_gateway.clone());
-                    trace!(
-                        "found better path through gateways: {} -> {}. total latency: {:?}",
-                        from_gateway,
-                        intermediate_gateway,
-                        total_latency
-                    );
+        let gateway_ids: HashSet<String> = self
+            .connection()
+            .smembers(self.key("gateways"))
+            .await
+            .context("Failed to get gateways")?;
+        let mut mesh = HashMap::new();
+        for from_gateway in gateway_ids {
+            let mut latencies = HashMap::new();
+            for (to_gateway, stats) in self
+                .hgetall::<GatewayToGatewayStats>(&self.mesh_key(&from_gateway))
+                .await?
+            {
+                if !stats.is_expired(self.gateway_stats_max_age) {
+                    expires(stats.last_updated, self.gateway_stats_max_age);
+                    latencies.insert(to_gateway, stats.latency);
                 }
             }
+            mesh.insert(from_gateway, latencies);
         }
 
-        best_gateway.map(|gateway| {
-            debug!(
-                "calculated optimal path for service: {}. gateway: {}, latency: {:?}",
-                service_id, gateway, best_latency
-            );
-            (gateway, best_latency)
-        })
+        let paths = shortest_service_paths(&self.local_gateway, &mesh, &services);
+        Ok((paths, valid_until))
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<String> {
+    serde_json::to_string(value).context("Failed to serialize store value")
+}
+
+fn decode<T: DeserializeOwned>(value: &str) -> Result<T> {
+    serde_json::from_str(value).context("Failed to deserialize store value")
+}
+
+// these need a local redis-server, run them with `cargo test -- --ignored`
+// and point REDIS_URL at it when it isn't on the default port
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store(local_gateway: &str) -> RedisStore {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        // every test gets its own keys so they can run in parallel
+        let key_prefix = format!(
+            "pluto-test-{}",
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        );
+        RedisStore::new(
+            local_gateway.to_string(),
+            &RedisStoreConfig { url, key_prefix },
+            &StoreConfig::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn service_stats(gateway_id: &str, latency: Duration) -> GatewayLatencyStats {
+        let mut stats =
+            GatewayLatencyStats::new(gateway_id.to_string(), "127.0.0.1:8080".to_string());
+        stats.stats.insert(
+            "service1".to_string(),
+            crate::common::types::ServiceStat {
+                latency,
+                service_id: "service1".to_string(),
+                status: ServiceStatus::Up,
+                error: None,
+            },
+        );
+        stats
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_shared_state_between_gateways() {
+        let gateway1 = store("gateway1").await;
+        // a second gateway process sharing the same keys
+        let gateway2 = RedisStore {
+            local_gateway: "gateway2".to_string(),
+            key_prefix: gateway1.key_prefix.clone(),
+            connection: gateway1.connection(),
+            service_stats_max_age: gateway1.service_stats_max_age,
+            gateway_stats_max_age: gateway1.gateway_stats_max_age,
+        };
+
+        gateway1
+            .update_gateway_to_service_stats(
+                service_stats("gateway1", Duration::from_millis(50)),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        gateway2
+            .update_gateway_to_service_stats(
+                service_stats("gateway2", Duration::from_millis(10)),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+        gateway1
+            .update_gateway_to_gateway_stats(
+                "gateway1".to_string(),
+                "gateway2".to_string(),
+                Duration::from_millis(5),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+
+        let path = gateway1
+            .get_optimal_service_path("service1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(path.hops, vec!["gateway1", "gateway2"]);
+        assert_eq!(path.latency, Duration::from_millis(15));
+        // cached now, and served from the cache until the stats change
+        let paths = gateway1.get_service_paths("service1").await.unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].hops, vec!["gateway1", "gateway2"]);
+        assert_eq!(
+            gateway2
+                .get_gateway_to_gateway_stats("gateway1", "gateway2")
+                .await
+                .unwrap()
+                .unwrap()
+                .latency,
+            Duration::from_millis(5)
+        );
+
+        gateway2.remove_gateway("gateway2").await.unwrap();
+        assert!(gateway1
+            .get_gateway_to_service_stats("gateway2", "service1")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            gateway1
+                .get_optimal_service_path("service1")
+                .await
+                .unwrap()
+                .unwrap()
+                .hops,
+            vec!["gateway1"]
+        );
+        let paths = gateway1.get_service_paths("service1").await.unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].hops, vec!["gateway1"]);
+    }
+
+    async fn mesh_latency(store: &RedisStore) -> Option<Duration> {
+        store
+            .get_gateway_to_gateway_stats("gateway1", "gateway2")
+            .await
+            .unwrap()
+            .map(|stats| stats.latency)
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_outdated_stats_are_discarded() {
+        let store = store("gateway1").await;
+        let now = SystemTime::now();
+        let earlier = now - Duration::from_secs(1);
+
+        for (latency, reported_at) in [(5, now), (50, earlier)] {
+            store
+                .update_gateway_to_gateway_stats(
+                    "gateway1".to_string(),
+                    "gateway2".to_string(),
+                    Duration::from_millis(latency),
+                    reported_at,
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(mesh_latency(&store).await, Some(Duration::from_millis(5)));
+
+        // only a removal reported after the stat removes it
+        store
+            .remove_gateway_to_gateway_stats("gateway1", "gateway2", earlier)
+            .await
+            .unwrap();
+        assert_eq!(mesh_latency(&store).await, Some(Duration::from_millis(5)));
+        store
+            .remove_gateway_to_gateway_stats("gateway1", "gateway2", SystemTime::now())
+            .await
+            .unwrap();
+        assert_eq!(mesh_latency(&store).await, None);
+
+        let mut stats = service_stats("gateway1", Duration::from_millis(10));
+        store
+            .update_gateway_to_service_stats(stats.clone(), now)
+            .await
+            .unwrap();
+        stats.stats.get_mut("service1").unwrap().latency = Duration::from_millis(99);
+        store
+            .update_gateway_to_service_stats(stats, earlier)
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .get_gateway_to_service_stats("gateway1", "service1")
+                .await
+                .unwrap()
+                .unwrap()
+                .latency,
+            Duration::from_millis(10)
+        );
+
+        // stats stored before they carried reported_at are older than any
+        store
+            .connection()
+            .hset::<_, _, _, ()>(
+                store.mesh_key("gateway1"),
+                "gateway2",
+                r#"{"latency":{"secs":0,"nanos":1000000},"last_updated":{"secs_since_epoch":1,"nanos_since_epoch":0}}"#,
+            )
+            .await
+            .unwrap();
+        store
+            .update_gateway_to_gateway_stats(
+                "gateway1".to_string(),
+                "gateway2".to_string(),
+                Duration::from_millis(7),
+                earlier,
+            )
+            .await
+            .unwrap();
+        assert_eq!(mesh_latency(&store).await, Some(Duration::from_millis(7)));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_expired_stats_are_evicted() {
+        let store = store("gateway1").await;
+        store
+            .update_gateway_to_service_stats(
+                service_stats("gateway1", Duration::from_millis(10)),
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+
+        let stale = GatewayToServiceStats {
+            service_id: "service1".to_string(),
+            latency: Duration::from_millis(10),
+            status: ServiceStatus::Up,
+            error: None,
+            last_updated: SystemTime::now() - Duration::from_secs(60),
+            reported_at: SystemTime::now() - Duration::from_secs(60),
+        };
+        store
+            .connection()
+            .hset::<_, _, _, ()>(
+                store.service_key("service1"),
+                "gateway1",
+                encode(&stale).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(store
+            .get_gateway_to_service_stats("gateway1", "service1")
+            .await
+            .unwrap()
+            .is_none());
+
+        store.evict_expired().await.unwrap();
+        assert!(store
+            .hget::<GatewayToServiceStats>(&store.service_key("service1"), "gateway1")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_optimal_service_path("service1")
+            .await
+            .unwrap()
+            .is_none());
     }
 }
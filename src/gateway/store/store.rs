@@ -1,48 +1,123 @@
 use std::time::{Duration, SystemTime};
 
-use crate::common::types::GatewayLatencyStats;
+use crate::common::types::{GatewayLatencyStats, ServiceStatus};
+use anyhow::Result;
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GatewayToServiceStats {
     pub service_id: String,
     pub latency: Duration,
+    pub status: ServiceStatus,
+    pub error: Option<String>,
     pub last_updated: SystemTime,
+    // when the reporting gateway sent the stat, orders its reports
+    #[serde(default = "unix_epoch")]
+    pub reported_at: SystemTime,
+}
+
+impl GatewayToServiceStats {
+    pub fn is_expired(&self, max_age: Duration) -> bool {
+        is_expired(self.last_updated, max_age)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GatewayToGatewayStats {
     pub latency: Duration,
     pub last_updated: SystemTime,
+    // when the probing gateway sent the stat, orders its reports
+    #[serde(default = "unix_epoch")]
+    pub reported_at: SystemTime,
+}
+
+impl GatewayToGatewayStats {
+    pub fn is_expired(&self, max_age: Duration) -> bool {
+        is_expired(self.last_updated, max_age)
+    }
+}
+
+fn unix_epoch() -> SystemTime {
+    SystemTime::UNIX_EPOCH
+}
+
+fn is_expired(last_updated: SystemTime, max_age: Duration) -> bool {
+    SystemTime::now()
+        .duration_since(last_updated)
+        .unwrap_or_default()
+        > max_age
+}
+
+// A path from the local gateway to a gateway serving the service, the hops
+// start with the local gateway and end with the serving one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServicePath {
+    pub hops: Vec<String>,
+    pub latency: Duration,
+}
+
+impl ServicePath {
+    // the gateway serving the service
+    pub fn gateway(&self) -> &str {
+        self.hops.last().map(String::as_str).unwrap_or_default()
+    }
+
+    // the gateway a request is sent to next, the local gateway when it
+    // serves the service itself
+    pub fn next_hop(&self) -> &str {
+        self.hops
+            .get(1)
+            .or(self.hops.first())
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OptimalPath {
-    pub gateway: String,
-    pub latency: Duration,
+    pub path: ServicePath,
     pub last_updated: SystemTime,
 }
 
+#[async_trait]
 pub trait Store: Send + Sync + std::fmt::Debug {
-    fn new() -> Self
-    where
-        Self: Sized;
-    fn update_gateway_to_service_stats(&self, stats: GatewayLatencyStats);
-    fn update_gateway_to_gateway_stats(
+    // `reported_at` is when the stats were sent, stats reported before the
+    // ones already held are discarded
+    async fn update_gateway_to_service_stats(
+        &self,
+        stats: GatewayLatencyStats,
+        reported_at: SystemTime,
+    ) -> Result<()>;
+    async fn update_gateway_to_gateway_stats(
         &self,
         from_gateway: String,
         to_gateway: String,
         latency: Duration,
-    );
-    fn get_optimal_service_path(&self, service_id: &str) -> Option<(String, Duration)>;
-    fn get_gateway_to_service_stats(
+        reported_at: SystemTime,
+    ) -> Result<()>;
+    async fn remove_gateway_to_gateway_stats(
+        &self,
+        from_gateway: &str,
+        to_gateway: &str,
+        reported_at: SystemTime,
+    ) -> Result<()>;
+    // drops every stat reported by or measured towards the gateway
+    async fn remove_gateway(&self, gateway_id: &str) -> Result<()>;
+    // evicts stats older than their max age and recomputes affected paths
+    async fn evict_expired(&self) -> Result<()>;
+    async fn get_optimal_service_path(&self, service_id: &str) -> Result<Option<ServicePath>>;
+    // the shortest path to every gateway that can serve the service, best
+    // path first
+    async fn get_service_paths(&self, service_id: &str) -> Result<Vec<ServicePath>>;
+    async fn get_gateway_to_service_stats(
         &self,
         gateway_id: &str,
         service_id: &str,
-    ) -> Option<GatewayToServiceStats>;
-    fn get_gateway_to_gateway_stats(
+    ) -> Result<Option<GatewayToServiceStats>>;
+    async fn get_gateway_to_gateway_stats(
         &self,
         from_gateway: &str,
         to_gateway: &str,
-    ) -> Option<GatewayToGatewayStats>;
+    ) -> Result<Option<GatewayToGatewayStats>>;
 }
@@ -0,0 +1,130 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    common::types::{StorePaths, StoreQuery, TransportType},
+    transport::{
+        factory::create_transport,
+        pubsub::{Message, PubSub, PubSubManager, Sender},
+        topics::Topic,
+    },
+};
+
+use super::config::OrbitConfig;
+
+// Requests an operator makes of a running cluster. Sent over orbit's
+// transport but as a sender of its own, so replies don't end up at orbit
+pub struct Admin {
+    transport: PubSubManager,
+    timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Node<'a> {
+    Orbit,
+    Gateway(&'a str),
+}
+
+impl Admin {
+    // joins the cluster through its broker. Over QUIC orbit is the hub, which
+    // the transport config would make this bind as well rather than dial,
+    // and the memory transport doesn't reach beyond the process
+    pub async fn new(config: &OrbitConfig) -> Result<Self> {
+        if let TransportType::Quic | TransportType::Memory = config.orbit.transport.transport_type {
+            bail!(
+                "can't reach a cluster over the {:?} transport, only through a broker",
+                config.orbit.transport.transport_type
+            );
+        }
+        let transport = create_transport(&config.orbit.transport).await?;
+        Ok(Self::with_transport(config, transport))
+    }
+
+    pub fn with_transport(config: &OrbitConfig, transport: Arc<dyn PubSub>) -> Self {
+        let sender = Sender::orbit(&format!("{}-admin-{}", config.orbit.id, std::process::id()));
+        Self {
+            transport: PubSubManager::new(transport, &config.orbit.transport.cluster, sender),
+            timeout: config.orbit.transport.request_timeout,
+        }
+    }
+
+    // round trip to the node
+    pub async fn ping(&self, node: Node<'_>) -> Result<Duration> {
+        let topic = match node {
+            Node::Orbit => Topic::orbit_requests(),
+            Node::Gateway(gateway_id) => Topic::gateway_requests(gateway_id),
+        };
+        self.transport
+            .ping(&topic, self.timeout)
+            .await
+            .with_context(|| format!("failed to ping {:?}", node))
+    }
+
+    // the paths to the service the gateway's store holds, best path first
+    pub async fn query_store(&self, gateway_id: &str, service_id: &str) -> Result<StorePaths> {
+        let reply = self
+            .transport
+            .request(
+                &Topic::gateway_requests(gateway_id),
+                Message::StoreQuery(StoreQuery {
+                    service_id: service_id.to_string(),
+                }),
+                self.timeout,
+            )
+            .await
+            .with_context(|| format!("failed to query store of gateway {}", gateway_id))?;
+        match reply.message {
+            Message::StorePaths(paths) => Ok(paths),
+            other => bail!("expected store paths from {}, got {:?}", gateway_id, other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_new_rejects_brokerless_transports() {
+        for transport in [
+            r#"{ type = "memory" }"#,
+            r#"{
+              type = "quic"
+              quic { listen_address = "127.0.0.1:0" }
+            }"#,
+        ] {
+            let config: OrbitConfig = hcl::from_str(&format!(
+                r#"
+                orbit {{
+                  listen_port     = 9090
+                  max_connections = 10
+                  transport {transport}
+                  heartbeat {{
+                    interval = "1s"
+                    timeout  = "1s"
+                    retries  = 3
+                  }}
+                  load_balancing {{ method = "round_robin" }}
+                  security {{
+                    ssl_enabled = false
+                    cert_file   = ""
+                    key_file    = ""
+                  }}
+                  logging {{
+                    level = "debug"
+                    file  = ""
+                  }}
+                  metrics {{
+                    enabled  = false
+                    endpoint = "/metrics"
+                  }}
+                }}
+                "#
+            ))
+            .unwrap();
+            let err = Admin::new(&config).await.err().unwrap();
+            assert!(err.to_string().contains("only through a broker"));
+        }
+    }
+}
@@ -9,6 +9,9 @@ pub struct OrbitConfig {
 
 #[derive(Debug, Deserialize)]
 pub struct Orbit {
+    // names orbit in the subjects it publishes on
+    #[serde(default = "default_orbit_id")]
+    pub id: String,
     pub listen_port: u16,
     pub max_connections: u32,
     pub transport: TransportConfig,
@@ -70,6 +73,10 @@ pub struct MetricsConfig {
     pub endpoint: String,
 }
 
+fn default_orbit_id() -> String {
+    "orbit".to_string()
+}
+
 pub fn read_orbit_config() -> Result<OrbitConfig, Box<dyn std::error::Error>> {
     let config_path =
         std::env::var("ORBIT_CONFIG_PATH").unwrap_or_else(|_| "config-orbit.hcl".to_string());
@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::common::types::{GatewayHeartbeat, GatewayStatus};
+
+#[derive(Debug, Clone)]
+pub struct GatewayEntry {
+    pub heartbeat: GatewayHeartbeat,
+    pub status: GatewayStatus,
+    pub last_seen: Instant,
+}
+
+// Tracks the liveness of every gateway that ever sent orbit a heartbeat
+#[derive(Debug, Default)]
+pub struct GatewayRegistry {
+    gateways: RwLock<HashMap<String, GatewayEntry>>,
+}
+
+impl GatewayRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // records a heartbeat, returns true when the gateway was unknown or dead
+    pub fn record_heartbeat(&self, heartbeat: GatewayHeartbeat, now: Instant) -> bool {
+        debug!("received heartbeat from gateway: {}", heartbeat.gateway_id);
+        let mut gateways = self.gateways.write().unwrap();
+        let revived = gateways
+            .get(&heartbeat.gateway_id)
+            .is_none_or(|entry| entry.status == GatewayStatus::Dead);
+        if revived {
+            info!("gateway {} is alive", heartbeat.gateway_id);
+        }
+
+        gateways.insert(
+            heartbeat.gateway_id.clone(),
+            GatewayEntry {
+                heartbeat,
+                status: GatewayStatus::Alive,
+                last_seen: now,
+            },
+        );
+        revived
+    }
+
+    // marks every alive gateway not heard from within `max_silence` as dead
+    // and returns the ids of the gateways that just died
+    pub fn expire(&self, now: Instant, max_silence: Duration) -> Vec<String> {
+        let mut gateways = self.gateways.write().unwrap();
+        let mut dead = Vec::new();
+        for (gateway_id, entry) in gateways.iter_mut() {
+            if entry.status == GatewayStatus::Alive
+                && now.saturating_duration_since(entry.last_seen) > max_silence
+            {
+                warn!(
+                    "gateway {} missed heartbeats for {:?}, marking dead",
+                    gateway_id, max_silence
+                );
+                entry.status = GatewayStatus::Dead;
+                dead.push(gateway_id.clone());
+            }
+        }
+        dead
+    }
+
+    pub fn get(&self, gateway_id: &str) -> Option<GatewayEntry> {
+        self.gateways.read().unwrap().get(gateway_id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<GatewayEntry> {
+        self.gateways.read().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(gateway_id: &str) -> GatewayHeartbeat {
+        GatewayHeartbeat {
+            gateway_id: gateway_id.to_string(),
+            region: "ap-south-1".to_string(),
+            address: "127.0.0.1:8080".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_gateway_dies_after_missed_heartbeats() {
+        let registry = GatewayRegistry::new();
+        let start = Instant::now();
+        let max_silence = Duration::from_secs(30);
+
+        assert!(registry.record_heartbeat(heartbeat("gateway1"), start));
+        assert!(!registry.record_heartbeat(heartbeat("gateway1"), start));
+
+        assert!(registry
+            .expire(start + Duration::from_secs(10), max_silence)
+            .is_empty());
+        assert_eq!(
+            registry.expire(start + Duration::from_secs(31), max_silence),
+            vec!["gateway1".to_string()]
+        );
+        assert_eq!(
+            registry.get("gateway1").unwrap().status,
+            GatewayStatus::Dead
+        );
+
+        // already dead gateways are only reported once
+        assert!(registry
+            .expire(start + Duration::from_secs(60), max_silence)
+            .is_empty());
+
+        assert!(registry.record_heartbeat(heartbeat("gateway1"), start + Duration::from_secs(61)));
+        assert_eq!(
+            registry.get("gateway1").unwrap().status,
+            GatewayStatus::Alive
+        );
+    }
+}
@@ -1,3 +1,6 @@
+pub mod admin;
 pub mod config;
+pub mod health_check;
 pub mod latency_sync;
+#[allow(clippy::module_inception)]
 pub mod orbit;
@@ -1,65 +1,93 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use log::debug;
 use tokio::task::JoinHandle;
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 
 use crate::{
     common::{
         error::Error,
-        types::{GatewayLatencyStats, TransportType},
+        types::{GatewayStatus, GatewayStatusUpdate},
     },
     transport::{
-        self,
-        pubsub::{Message, PubSubManager},
-        topics::PubSubTopics,
+        factory::create_transport,
+        pubsub::{Envelope, Message, PubSub, PubSubManager, Sender},
+        topics::{PubSubTopics, Target, Topic},
     },
 };
 
 use super::config::OrbitConfig;
+use super::health_check::GatewayRegistry;
 
 pub struct Orbit {
     pub config: OrbitConfig,
-    transport: Arc<PubSubManager<transport::nats::NatsPubSub>>,
+    transport: Arc<PubSubManager>,
+    registry: GatewayRegistry,
+    // the stats last relayed of every live gateway, by kind and gateway, for
+    // gateways to catch up on
+    latest: Mutex<HashMap<(PubSubTopics, String), Envelope>>,
 }
 
 impl Orbit {
     pub async fn new(config: OrbitConfig) -> Result<Self> {
-        let transport = Self::create_transport(&config).await?;
-        let manager = Arc::new(PubSubManager::new(transport));
+        let transport = create_transport(&config.orbit.transport).await?;
+        Ok(Self::with_transport(config, transport))
+    }
 
-        Ok(Self {
+    // builds an orbit on an already connected transport, e.g. a memory
+    // transport shared with gateways in the same process
+    pub fn with_transport(config: OrbitConfig, transport: Arc<dyn PubSub>) -> Self {
+        let manager = Arc::new(
+            PubSubManager::new(
+                transport,
+                &config.orbit.transport.cluster,
+                Sender::orbit(&config.orbit.id),
+            )
+            .with_backpressure(config.orbit.transport.backpressure.clone()),
+        );
+
+        Self {
             config,
             transport: manager,
-        })
+            registry: GatewayRegistry::new(),
+            latest: Mutex::new(HashMap::new()),
+        }
     }
 
-    async fn create_transport(conf: &OrbitConfig) -> Result<transport::nats::NatsPubSub> {
-        match conf.orbit.transport.transport_type {
-            TransportType::Nats => {
-                let nats_config = conf
-                    .orbit
-                    .transport
-                    .nats
-                    .clone()
-                    .context("NATS configuration missing")?;
-                transport::nats::NatsPubSub::new(nats_config)
-                    .await
-                    .context("Failed to create NATS PubSub")
-            }
-            _ => anyhow::bail!("Invalid transport type"),
-        }
+    // subject orbit publishes `topic` to every gateway on
+    fn publish_topic(&self, topic: PubSubTopics) -> Topic {
+        topic.orbit_subject(&self.config.orbit.id, Target::All)
     }
 
     pub async fn run(self: &Arc<Self>) -> Result<()> {
         let stats_receiver = self.spawn_stats_receiver();
+        let heartbeat_receiver = self.spawn_heartbeat_receiver();
+        let liveness_checker = self.spawn_liveness_checker();
+        let failover_receiver = self.spawn_failover_receiver();
+        let request_handler = self.spawn_request_handler();
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
                 error!("Received shutdown signal");
             }
             res = stats_receiver => {
-                let _ = res.context("Stats receiver task failed")?;
+                res.context("Stats receiver task failed")??;
+            }
+            res = heartbeat_receiver => {
+                res.context("Heartbeat receiver task failed")??;
+            }
+            res = liveness_checker => {
+                res.context("Liveness checker task failed")??;
+            }
+            res = failover_receiver => {
+                res.context("Failover receiver task failed")??;
+            }
+            res = request_handler => {
+                res.context("Request handler task failed")??;
             }
         }
         Ok(())
@@ -71,32 +99,216 @@ impl Orbit {
         tokio::spawn(async move { self_clone.start_receiving_stats().await })
     }
 
+    fn spawn_heartbeat_receiver(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning heartbeat receiver");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_receiving_heartbeats().await })
+    }
+
+    fn spawn_liveness_checker(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning liveness checker");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_checking_liveness().await })
+    }
+
+    fn spawn_failover_receiver(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning failover receiver");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_receiving_failovers().await })
+    }
+
+    fn spawn_request_handler(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        debug!("spawning request handler");
+        let self_clone = Arc::clone(self);
+        tokio::spawn(async move { self_clone.start_answering_requests().await })
+    }
+
     async fn start_receiving_stats(&self) -> Result<()> {
         info!("starting receiving stats");
         let mut rcv = self
             .transport
-            .subscribe_to_topics(&[PubSubTopics::GatewayToOrbitStats])
+            .subscribe_to_topics(&[
+                PubSubTopics::GatewayToOrbitStats.gateway_pattern(Target::All),
+                PubSubTopics::GatewayToOrbitMeshStats.gateway_pattern(Target::All),
+            ])
             .await
             .context("failed to subscribe to topics")?;
 
-        while let Some(msg) = rcv.recv().await {
-            if let Message::GatewayLatencyStats(stats) = msg {
-                trace!("received stats: {:?}", stats);
-                self.broadcast_stats(stats).await?;
+        while let Some(envelope) = rcv.recv().await {
+            let topic = match envelope.message {
+                Message::GatewayLatencyStats(_) => PubSubTopics::OrbitToGatewayStats,
+                Message::GatewayMeshStats(_) => PubSubTopics::OrbitToGatewayMeshStats,
+                _ => continue,
+            };
+            trace!("received stats: {:?}", envelope);
+            self.latest
+                .lock()
+                .unwrap()
+                .insert((topic, envelope.sender.id.clone()), envelope.clone());
+            if let Err(e) = self.broadcast_stats(topic, envelope).await {
+                warn!("{}", e);
             }
         }
-        Ok(())
+        bail!("subscription to stats lost")
     }
 
-    async fn broadcast_stats(&self, stats: GatewayLatencyStats) -> Result<(), Error> {
+    // stats are relayed as sent, so gateways still see who measured them and
+    // when
+    async fn broadcast_stats(&self, topic: PubSubTopics, stats: Envelope) -> Result<(), Error> {
         info!("broadcasting stats");
+        self.transport
+            .forward(&[self.publish_topic(topic)], stats)
+            .await
+            .context("failed to broadcast stats")
+            .map_err(|e| Error::PublishError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn start_receiving_heartbeats(&self) -> Result<()> {
+        info!("starting receiving heartbeats");
+        let mut rcv = self
+            .transport
+            .subscribe_to_topics(&[
+                PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::All)
+            ])
+            .await
+            .context("failed to subscribe to topics")?;
+
+        // the last heartbeat of every gateway, when the transport retains
+        // them, recorded as of when it was sent. Gateways that went silent
+        // while orbit was down are left out
+        let mut replayed = self
+            .transport
+            .replay(&[PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::All)])
+            .await
+            .context("failed to replay heartbeats")?;
+        while let Some(envelope) = replayed.recv().await {
+            let age = envelope.sent_at.elapsed().unwrap_or_default();
+            if age > self.max_silence() {
+                continue;
+            }
+            if let Some(sent_at) = Instant::now().checked_sub(age) {
+                self.handle_heartbeat(envelope, sent_at).await;
+            }
+        }
+
+        while let Some(envelope) = rcv.recv().await {
+            self.handle_heartbeat(envelope, Instant::now()).await;
+        }
+        bail!("subscription to heartbeats lost")
+    }
+
+    async fn handle_heartbeat(&self, envelope: Envelope, received_at: Instant) {
+        if let Message::GatewayHeartbeat(heartbeat) = envelope.message {
+            let gateway_id = heartbeat.gateway_id.clone();
+            if self.registry.record_heartbeat(heartbeat, received_at) {
+                if let Err(e) = self
+                    .broadcast_status(gateway_id, GatewayStatus::Alive)
+                    .await
+                {
+                    warn!("{}", e);
+                }
+            }
+        }
+    }
+
+    async fn start_receiving_failovers(&self) -> Result<()> {
+        info!("starting receiving failovers");
+        let mut rcv = self
+            .transport
+            .subscribe_to_topics(&[
+                PubSubTopics::PublishGatewayFailover.gateway_pattern(Target::All)
+            ])
+            .await
+            .context("failed to subscribe to topics")?;
+
+        while let Some(envelope) = rcv.recv().await {
+            if let Message::GatewayFailover(event) = &envelope.message {
+                warn!(
+                    "gateway {} failed over service {} from gateway {}: {}",
+                    event.gateway_id, event.service_id, event.failed_gateway, event.reason
+                );
+                if let Err(e) = self
+                    .transport
+                    .forward(
+                        &[self.publish_topic(PubSubTopics::SubscribeGatewayFailover)],
+                        envelope,
+                    )
+                    .await
+                {
+                    warn!("failed to broadcast failover event: {}", e);
+                }
+            }
+        }
+        bail!("subscription to failover events lost")
+    }
+
+    // a gateway is dead once it missed `retries` heartbeat intervals, with
+    // `timeout` as slack for the last heartbeat in flight
+    fn max_silence(&self) -> Duration {
+        let heartbeat = &self.config.orbit.heartbeat;
+        heartbeat.interval * heartbeat.retries as u32 + heartbeat.timeout
+    }
+
+    async fn start_checking_liveness(&self) -> Result<()> {
+        info!("starting checking gateway liveness");
+        let max_silence = self.max_silence();
+        let mut interval = tokio::time::interval(self.config.orbit.heartbeat.interval);
+
+        loop {
+            interval.tick().await;
+            for gateway_id in self.registry.expire(Instant::now(), max_silence) {
+                self.latest
+                    .lock()
+                    .unwrap()
+                    .retain(|(_, id), _| *id != gateway_id);
+                if let Err(e) = self.broadcast_status(gateway_id, GatewayStatus::Dead).await {
+                    warn!("{}", e);
+                }
+            }
+        }
+    }
+
+    // answers pings and gateways asking for a snapshot on startup
+    async fn start_answering_requests(&self) -> Result<()> {
+        info!("starting answering requests");
+        let mut rcv = self
+            .transport
+            .subscribe_to_topics(&[Topic::orbit_requests()])
+            .await
+            .context("failed to subscribe to topics")?;
+
+        while let Some(request) = rcv.recv().await {
+            let reply = match request.message {
+                Message::Ping => Message::Pong,
+                Message::SnapshotRequest => {
+                    Message::Snapshot(self.latest.lock().unwrap().values().cloned().collect())
+                }
+                _ => Message::RequestFailed("not a request orbit answers".to_string()),
+            };
+            if let Err(e) = self.transport.reply(&request, reply).await {
+                warn!(
+                    "failed to reply to request from {}: {}",
+                    request.sender.id, e
+                );
+            }
+        }
+        bail!("subscription to requests lost")
+    }
+
+    async fn broadcast_status(
+        &self,
+        gateway_id: String,
+        status: GatewayStatus,
+    ) -> Result<(), Error> {
+        info!("broadcasting status {:?} of gateway {}", status, gateway_id);
         self.transport
             .broadcast(
-                &[PubSubTopics::OrbitToGatewayStats],
-                Message::GatewayLatencyStats(stats),
+                &[self.publish_topic(PubSubTopics::SubscribeGatewayHeartbeat)],
+                Message::GatewayStatus(GatewayStatusUpdate { gateway_id, status }),
             )
             .await
-            .context("failed to broadcast stats")
+            .context("failed to broadcast gateway status")
             .map_err(|e| Error::PublishError(e.to_string()))?;
         Ok(())
     }
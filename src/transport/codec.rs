@@ -0,0 +1,184 @@
+use crate::common::error::Error;
+use crate::common::types::{CodecConfig, WireFormat};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Read;
+
+// Every payload starts with a header byte naming its format and whether it
+// is compressed, so a subscriber decodes whatever the publisher picked
+const JSON: u8 = 0x01;
+const MSGPACK: u8 = 0x02;
+const FORMAT_MASK: u8 = 0x0f;
+const ZSTD: u8 = 0x80;
+// payloads from before the header was introduced are bare json objects
+const LEGACY_JSON: u8 = b'{';
+const ZSTD_LEVEL: i32 = 3;
+// anyone can publish, so a compressed payload is only inflated this far
+const MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+// what brokers that label payloads are told, the header byte rather than
+// the label says how to decode them
+pub const CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Codec {
+    format: WireFormat,
+    compress_above: Option<usize>,
+}
+
+impl Codec {
+    pub fn new(conf: &CodecConfig) -> Self {
+        Self {
+            format: conf.format,
+            compress_above: conf.compress_above,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        let (header, body) = match self.format {
+            WireFormat::Json => (JSON, serde_json::to_vec(value).map_err(serialization)?),
+            // named, so fields added with a serde default stay compatible
+            WireFormat::MsgPack => (
+                MSGPACK,
+                rmp_serde::to_vec_named(value).map_err(serialization)?,
+            ),
+        };
+
+        if self.compress_above.is_some_and(|limit| body.len() > limit) {
+            let compressed = zstd::encode_all(&body[..], ZSTD_LEVEL).map_err(serialization)?;
+            return Ok([&[header | ZSTD][..], &compressed].concat());
+        }
+        Ok([&[header][..], &body].concat())
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, Error> {
+        let (&header, body) = payload
+            .split_first()
+            .ok_or_else(|| Error::DeserializationError("empty payload".to_string()))?;
+        if header == LEGACY_JSON {
+            return serde_json::from_slice(payload).map_err(deserialization);
+        }
+
+        let decompressed;
+        let body = if header & ZSTD != 0 {
+            decompressed = decompress(body)?;
+            &decompressed[..]
+        } else {
+            body
+        };
+        match header & FORMAT_MASK {
+            JSON => serde_json::from_slice(body).map_err(deserialization),
+            MSGPACK => rmp_serde::from_slice(body).map_err(deserialization),
+            format => Err(Error::DeserializationError(format!(
+                "unknown wire format {:#04x}",
+                format
+            ))),
+        }
+    }
+}
+
+fn decompress(body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decompressed = Vec::new();
+    zstd::stream::Decoder::new(body)
+        .map_err(deserialization)?
+        .take(MAX_DECOMPRESSED_LEN as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(deserialization)?;
+    if decompressed.len() > MAX_DECOMPRESSED_LEN {
+        return Err(Error::DeserializationError(format!(
+            "payload decompresses to more than {} bytes",
+            MAX_DECOMPRESSED_LEN
+        )));
+    }
+    Ok(decompressed)
+}
+
+fn serialization(e: impl std::fmt::Display) -> Error {
+    Error::SerializationError(e.to_string())
+}
+
+fn deserialization(e: impl std::fmt::Display) -> Error {
+    Error::DeserializationError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::GatewayMeshStats;
+    use crate::transport::pubsub::{Envelope, Message, Sender};
+    use std::time::Duration;
+
+    fn envelope() -> Envelope {
+        let mut stats = GatewayMeshStats::new("gateway1".to_string());
+        for i in 0..100 {
+            stats
+                .latencies
+                .insert(format!("gateway{}", i), Duration::from_millis(i));
+        }
+        Envelope::new(
+            "default",
+            Sender::gateway("gateway1"),
+            7,
+            Message::GatewayMeshStats(stats),
+        )
+    }
+
+    fn codec(format: WireFormat, compress_above: Option<usize>) -> Codec {
+        Codec::new(&CodecConfig {
+            format,
+            compress_above,
+        })
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = codec(WireFormat::Json, None).encode(&envelope()).unwrap();
+        let msgpack = codec(WireFormat::MsgPack, None)
+            .encode(&envelope())
+            .unwrap();
+        let compressed = codec(WireFormat::MsgPack, Some(1024))
+            .encode(&envelope())
+            .unwrap();
+        assert!(msgpack.len() < json.len());
+        assert!(compressed.len() < msgpack.len());
+
+        // any codec decodes what any other encoded
+        let decoder = Codec::default();
+        for payload in [json, msgpack, compressed] {
+            let decoded: Envelope = decoder.decode(&payload).unwrap();
+            assert_eq!(decoded.sequence, 7);
+            assert_eq!(decoded.sender, Sender::gateway("gateway1"));
+            assert!(matches!(
+                decoded.message,
+                Message::GatewayMeshStats(stats) if stats.latencies.len() == 100
+            ));
+        }
+    }
+
+    #[test]
+    fn test_small_payloads_are_not_compressed() {
+        let message = Envelope::new("default", Sender::orbit("orbit"), 0, Message::Ping);
+        let payload = codec(WireFormat::Json, Some(1024))
+            .encode(&message)
+            .unwrap();
+        assert_eq!(payload[0], JSON);
+    }
+
+    #[test]
+    fn test_legacy_and_unknown_payloads() {
+        let message = Envelope::new("default", Sender::orbit("orbit"), 0, Message::Ping);
+        let legacy = serde_json::to_vec(&message).unwrap();
+        let decoded: Envelope = Codec::default().decode(&legacy).unwrap();
+        assert!(matches!(decoded.message, Message::Ping));
+
+        assert!(Codec::default().decode::<Envelope>(&[0x0e, 0x00]).is_err());
+        assert!(Codec::default().decode::<Envelope>(&[]).is_err());
+    }
+
+    #[test]
+    fn test_decompression_is_bounded() {
+        let bomb = zstd::encode_all(&vec![b' '; MAX_DECOMPRESSED_LEN + 1][..], ZSTD_LEVEL).unwrap();
+        let payload = [&[JSON | ZSTD][..], &bomb].concat();
+        let err = Codec::default().decode::<Envelope>(&payload).unwrap_err();
+        assert!(err.to_string().contains("decompresses to more than"));
+    }
+}
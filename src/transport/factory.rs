@@ -0,0 +1,92 @@
+use crate::common::types::{TransportConfig, TransportType};
+use crate::transport::pubsub::PubSub;
+use anyhow::{Context, Error as AnyhowError};
+use std::sync::Arc;
+
+use super::codec::Codec;
+use super::kafka::KafkaPubSub;
+use super::memory::{MemoryBroker, MemoryPubSub};
+use super::nats::NatsPubSub;
+use super::quic::QuicPubSub;
+use super::rabbitmq::RabbitMQPubSub;
+use super::redis::RedisPubSub;
+
+// Connects the backend selected by the transport config
+pub async fn create_transport(conf: &TransportConfig) -> Result<Arc<dyn PubSub>, AnyhowError> {
+    let codec = Codec::new(&conf.codec);
+    match conf.transport_type {
+        TransportType::Nats => {
+            let nats_config = conf.nats.clone().context("NATS configuration missing")?;
+            let pubsub = NatsPubSub::new(nats_config, &conf.cluster, codec)
+                .await
+                .context("Failed to create NATS PubSub")?;
+            Ok(Arc::new(pubsub))
+        }
+        TransportType::Kafka => {
+            let kafka_config = conf.kafka.clone().context("Kafka configuration missing")?;
+            let pubsub = KafkaPubSub::new(kafka_config, codec)
+                .await
+                .context("Failed to create Kafka PubSub")?;
+            Ok(Arc::new(pubsub))
+        }
+        TransportType::RabbitMQ => {
+            let rabbitmq_config = conf
+                .rabbitmq
+                .clone()
+                .context("RabbitMQ configuration missing")?;
+            let pubsub = RabbitMQPubSub::new(rabbitmq_config, codec)
+                .await
+                .context("Failed to create RabbitMQ PubSub")?;
+            Ok(Arc::new(pubsub))
+        }
+        TransportType::Redis => {
+            let redis_config = conf.redis.clone().context("Redis configuration missing")?;
+            let pubsub = RedisPubSub::new(redis_config, codec)
+                .await
+                .context("Failed to create Redis PubSub")?;
+            Ok(Arc::new(pubsub))
+        }
+        TransportType::Memory => Ok(Arc::new(MemoryPubSub::new(MemoryBroker::global()))),
+        TransportType::Quic => {
+            let quic_config = conf.quic.clone().context("QUIC configuration missing")?;
+            let pubsub = QuicPubSub::new(quic_config, codec)
+                .await
+                .context("Failed to create QUIC PubSub")?;
+            Ok(Arc::new(pubsub))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::{BackpressureConfig, CodecConfig};
+    use std::time::Duration;
+
+    fn config(transport_type: TransportType) -> TransportConfig {
+        TransportConfig {
+            transport_type,
+            cluster: "default".to_string(),
+            codec: CodecConfig::default(),
+            backpressure: BackpressureConfig::default(),
+            request_timeout: Duration::from_secs(5),
+            nats: None,
+            kafka: None,
+            rabbitmq: None,
+            redis: None,
+            quic: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_transport() {
+        assert!(create_transport(&config(TransportType::Memory))
+            .await
+            .is_ok());
+
+        let err = create_transport(&config(TransportType::Nats))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("NATS configuration missing"));
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::VecDeque;
+use std::mem::{discriminant, Discriminant};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use super::pubsub::{Envelope, Message};
+use crate::common::types::{BackpressureConfig, OverflowPolicy};
+
+// Buffers what a subscription received until the subscriber gets to it, and
+// decides what gives once the subscriber falls behind
+#[derive(Debug)]
+pub struct Inbox {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+pub(crate) struct InboxSender {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    readable: Notify,
+    writable: Notify,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    envelopes: VecDeque<Envelope>,
+    closed: bool,
+}
+
+// what became of a pushed envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    Queued,
+    // replaced an older update of the same gateway still queued
+    Coalesced,
+    // queued after dropping the oldest envelope
+    Overflowed,
+    // the subscriber is gone
+    Closed,
+}
+
+pub(crate) fn inbox(conf: &BackpressureConfig) -> (InboxSender, Inbox) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue::default()),
+        capacity: conf.capacity.max(1),
+        overflow: conf.overflow,
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (
+        InboxSender {
+            shared: Arc::clone(&shared),
+        },
+        Inbox { shared },
+    )
+}
+
+impl Inbox {
+    // None once the sender is gone and everything queued was received
+    pub async fn recv(&mut self) -> Option<Envelope> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(envelope) = queue.envelopes.pop_front() {
+                    self.shared.writable.notify_one();
+                    return Some(envelope);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.writable.notify_one();
+    }
+}
+
+impl InboxSender {
+    pub(crate) async fn push(&self, envelope: Envelope) -> Pushed {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if queue.closed {
+                    return Pushed::Closed;
+                }
+                // only blocking leaves a full queue as it is
+                if !self.is_blocked(&queue) {
+                    let pushed = self.push_into(&mut queue, envelope);
+                    self.shared.readable.notify_one();
+                    return pushed;
+                }
+            }
+            self.shared.writable.notified().await;
+        }
+    }
+
+    fn is_blocked(&self, queue: &Queue) -> bool {
+        self.shared.overflow == OverflowPolicy::Block
+            && queue.envelopes.len() >= self.shared.capacity
+    }
+
+    fn push_into(&self, queue: &mut Queue, envelope: Envelope) -> Pushed {
+        if self.shared.overflow == OverflowPolicy::CoalesceLatestPerGateway {
+            if let Some(key) = coalescing_key(&envelope.message) {
+                let queued = queue
+                    .envelopes
+                    .iter_mut()
+                    .find(|queued| coalescing_key(&queued.message) == Some(key));
+                if let Some(queued) = queued {
+                    *queued = envelope;
+                    return Pushed::Coalesced;
+                }
+            }
+        }
+
+        if queue.envelopes.len() < self.shared.capacity {
+            queue.envelopes.push_back(envelope);
+            return Pushed::Queued;
+        }
+        queue.envelopes.pop_front();
+        queue.envelopes.push_back(envelope);
+        Pushed::Overflowed
+    }
+}
+
+impl Drop for InboxSender {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.readable.notify_one();
+    }
+}
+
+// messages that only matter as the latest state of a gateway, events are
+// never coalesced
+fn coalescing_key(message: &Message) -> Option<(Discriminant<Message>, &str)> {
+    let gateway_id = match message {
+        Message::GatewayLatencyStats(stats) => &stats.gateway_id,
+        Message::GatewayMeshStats(stats) => &stats.gateway_id,
+        Message::GatewayHeartbeat(heartbeat) => &heartbeat.gateway_id,
+        Message::GatewayStatus(update) => &update.gateway_id,
+        _ => return None,
+    };
+    Some((discriminant(message), gateway_id.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::{GatewayFailoverEvent, GatewayLatencyStats};
+    use crate::transport::pubsub::Sender;
+    use std::time::Duration;
+
+    fn conf(capacity: usize, overflow: OverflowPolicy) -> BackpressureConfig {
+        BackpressureConfig { capacity, overflow }
+    }
+
+    fn stats(gateway_id: &str, address: &str) -> Envelope {
+        let stats = GatewayLatencyStats::new(gateway_id.to_string(), address.to_string());
+        Envelope::new(
+            "default",
+            Sender::gateway(gateway_id),
+            0,
+            Message::GatewayLatencyStats(stats),
+        )
+    }
+
+    fn failover(gateway_id: &str) -> Envelope {
+        let event = GatewayFailoverEvent {
+            gateway_id: gateway_id.to_string(),
+            service_id: "llm".to_string(),
+            failed_gateway: "gateway3".to_string(),
+            reason: "timeout".to_string(),
+        };
+        Envelope::new(
+            "default",
+            Sender::gateway(gateway_id),
+            0,
+            Message::GatewayFailover(event),
+        )
+    }
+
+    fn address(envelope: Option<Envelope>) -> String {
+        match envelope.map(|envelope| envelope.message) {
+            Some(Message::GatewayLatencyStats(stats)) => stats.address,
+            other => panic!("expected latency stats, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let (tx, mut rx) = inbox(&conf(1, OverflowPolicy::Block));
+        assert_eq!(tx.push(stats("gateway1", "a")).await, Pushed::Queued);
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(50), tx.push(stats("gateway1", "b")));
+        assert!(blocked.await.is_err());
+
+        // room again once received, and the queue drains after the sender is gone
+        let pushed = tokio::spawn(async move {
+            let pushed = tx.push(stats("gateway1", "c")).await;
+            (tx, pushed)
+        });
+        assert_eq!(address(rx.recv().await), "a");
+        let (tx, pushed) = pushed.await.unwrap();
+        assert_eq!(pushed, Pushed::Queued);
+        drop(tx);
+        assert_eq!(address(rx.recv().await), "c");
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let (tx, mut rx) = inbox(&conf(2, OverflowPolicy::DropOldest));
+        for address in ["a", "b", "c"] {
+            tx.push(stats("gateway1", address)).await;
+        }
+        assert_eq!(address(rx.recv().await), "b");
+        assert_eq!(address(rx.recv().await), "c");
+
+        drop(rx);
+        assert_eq!(tx.push(stats("gateway1", "d")).await, Pushed::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_latest_per_gateway() {
+        let (tx, mut rx) = inbox(&conf(3, OverflowPolicy::CoalesceLatestPerGateway));
+        assert_eq!(tx.push(stats("gateway1", "a")).await, Pushed::Queued);
+        assert_eq!(tx.push(failover("gateway1")).await, Pushed::Queued);
+        assert_eq!(tx.push(stats("gateway2", "b")).await, Pushed::Queued);
+        // the newest stats take the place of the queued ones
+        assert_eq!(tx.push(stats("gateway1", "c")).await, Pushed::Coalesced);
+        assert_eq!(address(rx.recv().await), "c");
+
+        // events aren't coalesced, when full the oldest makes room
+        assert_eq!(tx.push(failover("gateway2")).await, Pushed::Queued);
+        assert_eq!(tx.push(stats("gateway3", "d")).await, Pushed::Overflowed);
+        assert_eq!(address(rx.recv().await), "b");
+    }
+}
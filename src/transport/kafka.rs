@@ -1 +1,230 @@
+use crate::common::error::Error;
+use crate::common::types::KafkaConfig;
+use crate::transport::codec::Codec;
+use crate::transport::pubsub::{decode_delivery, Delivery, Envelope, PubSub};
+use anyhow::{Context, Error as AnyhowError};
+use async_trait::async_trait;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::message::Message as _;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
 
+use super::topics::Topic;
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+const PATTERN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct KafkaPubSub {
+    conf: KafkaConfig,
+    producer: FutureProducer,
+    codec: Codec,
+}
+
+impl std::fmt::Debug for KafkaPubSub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KafkaPubSub")
+            .field("conf", &self.conf)
+            .finish()
+    }
+}
+
+impl KafkaPubSub {
+    pub async fn new(conf: KafkaConfig, codec: Codec) -> Result<Self, AnyhowError> {
+        let mut config = Self::client_config(&conf);
+        if let Some(max_message_bytes) = conf.max_message_bytes {
+            config.set("message.max.bytes", max_message_bytes.to_string());
+        }
+        let producer: FutureProducer =
+            config.create().context("Failed to create Kafka producer")?;
+
+        // the producer connects lazily, fetch the metadata so that bad
+        // brokers fail here rather than on the first publish
+        let client = producer.clone();
+        tokio::task::spawn_blocking(move || {
+            client
+                .client()
+                .fetch_metadata(None, METADATA_TIMEOUT)
+                .map(|_| ())
+        })
+        .await
+        .context("Failed to fetch Kafka metadata")?
+        .context("Failed to connect to Kafka brokers")?;
+
+        Ok(Self {
+            conf,
+            producer,
+            codec,
+        })
+    }
+
+    fn client_config(conf: &KafkaConfig) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", conf.brokers.join(","))
+            .set("client.id", &conf.client_id);
+        config
+    }
+
+    // every process subscribes in its own consumer group so that each of
+    // them sees every message instead of sharing the partitions
+    fn group_id(&self, topic: &str) -> String {
+        format!("{}.{}.{}", self.conf.group_id, self.conf.client_id, topic)
+    }
+}
+
+// every subject is a kafka topic of its own, patterns become regex
+// subscriptions which kafka marks with a leading `^`
+fn subscription(topic: &Topic) -> String {
+    if !topic.is_pattern() {
+        return topic.as_str().to_string();
+    }
+    let tokens: Vec<&str> = topic
+        .as_str()
+        .split('.')
+        .map(|token| if token == "*" { "[^.]+" } else { token })
+        .collect();
+    format!("^{}$", tokens.join("\\."))
+}
+
+#[async_trait]
+impl PubSub for KafkaPubSub {
+    async fn publish(&self, topic: Topic, message: Envelope) -> Result<(), AnyhowError> {
+        let payload = self.codec.encode(&message)?;
+        self.producer
+            .send(
+                FutureRecord::<(), _>::to(topic.as_str()).payload(&payload),
+                SEND_TIMEOUT,
+            )
+            .await
+            .map_err(|(e, _)| Error::PublishError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, AnyhowError> {
+        let is_pattern = topic.is_pattern();
+        let topic = subscription(&topic);
+        let mut config = Self::client_config(&self.conf);
+        config
+            .set("group.id", self.group_id(&topic))
+            .set("enable.auto.commit", "true")
+            // only messages published from now on, like the other transports
+            .set("auto.offset.reset", "latest")
+            .set("allow.auto.create.topics", "true");
+        if let Some(session_timeout_ms) = self.conf.session_timeout_ms {
+            config.set("session.timeout.ms", session_timeout_ms.to_string());
+        }
+        if is_pattern {
+            // topics of gateways that join later are only found on a
+            // metadata refresh
+            config.set(
+                "topic.metadata.refresh.interval.ms",
+                PATTERN_REFRESH_INTERVAL.as_millis().to_string(),
+            );
+        }
+
+        let consumer: StreamConsumer = config
+            .create()
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        consumer
+            .subscribe(&[&topic])
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        debug!("subscribed to kafka topic: {}", topic);
+
+        let (tx, rx) = mpsc::channel(100);
+        let codec = self.codec;
+        tokio::spawn(async move {
+            loop {
+                match consumer.recv().await {
+                    Ok(msg) => {
+                        let Some(payload) = msg.payload() else {
+                            continue;
+                        };
+                        let delivery = decode_delivery(codec, msg.topic(), payload);
+                        if tx.send(delivery).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("failed to receive from kafka topic {}: {}", topic, e),
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pubsub::{Message, Sender};
+    use crate::transport::topics::{PubSubTopics, Target};
+
+    #[test]
+    fn test_subscription() {
+        assert_eq!(
+            subscription(&PubSubTopics::GatewayToOrbitStats.gateway_subject("eu", "paris")),
+            "gateway.eu.paris.stats"
+        );
+        assert_eq!(
+            subscription(&PubSubTopics::GatewayToOrbitStats.gateway_pattern(Target::All)),
+            "^gateway\\.[^.]+\\.[^.]+\\.stats$"
+        );
+    }
+
+    // needs a local broker, run it with `cargo test -- --ignored` and point
+    // KAFKA_BROKERS at it when it isn't on the default port
+    #[tokio::test]
+    #[ignore]
+    async fn test_publish_subscribe() {
+        let brokers =
+            std::env::var("KAFKA_BROKERS").unwrap_or_else(|_| "localhost:9092".to_string());
+        let conf = |client_id: &str| KafkaConfig {
+            brokers: vec![brokers.clone()],
+            client_id: client_id.to_string(),
+            group_id: "pluto-test".to_string(),
+            max_message_bytes: None,
+            session_timeout_ms: None,
+        };
+        let publisher = KafkaPubSub::new(conf("publisher"), Codec::default())
+            .await
+            .unwrap();
+        let subscriber1 = KafkaPubSub::new(conf("subscriber1"), Codec::default())
+            .await
+            .unwrap();
+        let subscriber2 = KafkaPubSub::new(conf("subscriber2"), Codec::default())
+            .await
+            .unwrap();
+
+        let topic = PubSubTopics::PublishConfigUpdate.orbit_subject("orbit", Target::All);
+        let mut rx1 = subscriber1.subscribe(topic.clone()).await.unwrap();
+        let mut rx2 = subscriber2.subscribe(topic.clone()).await.unwrap();
+
+        // keep publishing until both consumer groups got their partitions
+        let receive_both = async {
+            let mut got1 = false;
+            let mut got2 = false;
+            while !(got1 && got2) {
+                publisher
+                    .publish(
+                        topic.clone(),
+                        Envelope::new("test", Sender::gateway("test"), 0, Message::Ping),
+                    )
+                    .await
+                    .unwrap();
+                tokio::select! {
+                    Some(Ok(Envelope { message: Message::Ping, .. })) = rx1.recv() => got1 = true,
+                    Some(Ok(Envelope { message: Message::Ping, .. })) = rx2.recv() => got2 = true,
+                    _ = tokio::time::sleep(Duration::from_millis(500)) => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), receive_both)
+            .await
+            .expect("both subscribers should receive the message");
+    }
+}
@@ -0,0 +1,186 @@
+use crate::transport::pubsub::{Delivery, Envelope, PubSub};
+use anyhow::Error as AnyhowError;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{trace, warn};
+
+use super::topics::Topic;
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+// Routes messages between the memory transports created from it, so orbit
+// and any number of gateways can talk inside a single process
+#[derive(Debug, Default)]
+pub struct MemoryBroker {
+    // subscription pattern -> channel shared by its subscribers
+    channels: RwLock<HashMap<String, broadcast::Sender<Delivery>>>,
+}
+
+impl MemoryBroker {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    // the broker behind every memory transport created from config
+    pub fn global() -> Arc<Self> {
+        static BROKER: OnceLock<Arc<MemoryBroker>> = OnceLock::new();
+        Arc::clone(BROKER.get_or_init(MemoryBroker::new))
+    }
+
+    pub(crate) fn publish(&self, subject: &str, message: Envelope) {
+        self.deliver(subject, Ok(message));
+    }
+
+    // also hands on what a transport relaying through the broker couldn't
+    // decode, so subscribers dead-letter it as they would their own
+    pub(crate) fn deliver(&self, subject: &str, delivery: Delivery) {
+        for (pattern, tx) in self.channels.read().unwrap().iter() {
+            if matches(pattern, subject) {
+                trace!("delivering message on {} to {}", subject, pattern);
+                // no receivers left is fine, like publishing without subscribers
+                let _ = tx.send(delivery.clone());
+            }
+        }
+    }
+
+    fn subscribe(&self, pattern: &str) -> broadcast::Receiver<Delivery> {
+        self.channels
+            .write()
+            .unwrap()
+            .entry(pattern.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+}
+
+// nats style matching, `*` matches a single token and `>` the rest
+pub(crate) fn matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(subject_token)) if token == subject_token => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryPubSub {
+    broker: Arc<MemoryBroker>,
+}
+
+impl MemoryPubSub {
+    pub fn new(broker: Arc<MemoryBroker>) -> Self {
+        Self { broker }
+    }
+}
+
+#[async_trait]
+impl PubSub for MemoryPubSub {
+    async fn publish(&self, topic: Topic, message: Envelope) -> Result<(), AnyhowError> {
+        self.broker.publish(topic.as_str(), message);
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, AnyhowError> {
+        let mut subscription = self.broker.subscribe(topic.as_str());
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(async move {
+            loop {
+                match subscription.recv().await {
+                    Ok(delivery) => {
+                        if tx.send(delivery).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "subscriber to {} lagged, skipped {} messages",
+                            topic.as_str(),
+                            skipped
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pubsub::{Message, Sender};
+    use crate::transport::topics::{PubSubTopics, Target};
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("orbit.latency.stats", "orbit.latency.stats"));
+        assert!(matches(
+            "orbit.*.latency.stats",
+            "orbit.gateway1.latency.stats"
+        ));
+        assert!(matches("orbit.>", "orbit.gateway1.heartbeat"));
+        assert!(!matches("orbit.*.latency.stats", "orbit.latency.stats"));
+        assert!(!matches("orbit.*", "orbit.gateway1.heartbeat"));
+        assert!(!matches("orbit.latency", "orbit.latency.stats"));
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let broker = MemoryBroker::new();
+        let publisher = MemoryPubSub::new(Arc::clone(&broker));
+        let subscriber = MemoryPubSub::new(Arc::clone(&broker));
+
+        let heartbeats = PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::All);
+        let heartbeat = PubSubTopics::PublishGatewayHeartbeat.gateway_subject("local", "gateway1");
+        let mut rx1 = subscriber.subscribe(heartbeats.clone()).await.unwrap();
+        let mut rx2 = subscriber.subscribe(heartbeats).await.unwrap();
+        let mut other = subscriber
+            .subscribe(PubSubTopics::GatewayToOrbitStats.gateway_pattern(Target::All))
+            .await
+            .unwrap();
+
+        publisher
+            .publish(
+                heartbeat.clone(),
+                Envelope::new("test", Sender::gateway("test"), 0, Message::Ping),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx1.recv().await,
+            Some(Ok(Envelope {
+                message: Message::Ping,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            rx2.recv().await,
+            Some(Ok(Envelope {
+                message: Message::Ping,
+                ..
+            }))
+        ));
+        assert!(other.try_recv().is_err());
+
+        // brokers don't share messages
+        let isolated = MemoryPubSub::new(MemoryBroker::new());
+        isolated
+            .publish(
+                heartbeat,
+                Envelope::new("test", Sender::gateway("test"), 0, Message::Pong),
+            )
+            .await
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert!(rx1.try_recv().is_err());
+    }
+}
@@ -1,3 +1,11 @@
+pub mod codec;
+pub mod factory;
+pub mod inbox;
+pub mod kafka;
+pub mod memory;
 pub mod nats;
 pub mod pubsub;
+pub mod quic;
+pub mod rabbitmq;
+pub mod redis;
 pub mod topics;
@@ -1,67 +1,517 @@
 use crate::common::error::Error;
-use crate::common::types::NatsConfig;
-use crate::transport::pubsub::{Message, PubSub};
-use anyhow::{Context, Error as AnyhowError};
-use async_nats::Client;
-use async_nats::ConnectOptions;
+use crate::common::types::{JetStreamConfig, NatsConfig};
+use crate::transport::codec::Codec;
+use crate::transport::pubsub::{decode_delivery, Delivery, Envelope, PubSub};
+use anyhow::{bail, ensure, Context, Error as AnyhowError};
+use async_nats::jetstream::consumer::pull::{Ordered, OrderedConfig};
+use async_nats::jetstream::consumer::{Consumer, DeliverPolicy};
+use async_nats::{jetstream, Client, ConnectOptions};
 use async_trait::async_trait;
 use futures_util::StreamExt;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
 
-use super::topics::PubSubTopics;
+use super::memory::matches;
+use super::topics::{token, PubSubTopics, Topic};
+
+const MAX_RECONNECT_WAIT: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug)]
 pub struct NatsPubSub {
     client: Arc<Client>,
+    jetstream: Option<JetStream>,
+    codec: Codec,
+}
+
+// the stream the latest state of the gateways is retained in, in JetStream
+// mode. Events, requests, replies and dead letters stay on core NATS, the
+// stream keeps only the last message of a subject and would drop them
+#[derive(Clone, Debug)]
+struct JetStream {
+    context: jetstream::Context,
+    stream: jetstream::stream::Stream,
+    subjects: Vec<String>,
 }
 
 impl NatsPubSub {
-    pub async fn new(conf: NatsConfig) -> Result<Self, AnyhowError> {
+    pub async fn new(conf: NatsConfig, cluster: &str, codec: Codec) -> Result<Self, AnyhowError> {
+        let client = Self::connect_options(&conf)
+            .await?
+            .connect(&conf.url)
+            .await
+            .context("Failed to connect to NATS server")?;
+        let jetstream = match &conf.jetstream {
+            Some(jetstream_config) => {
+                Some(JetStream::new(client.clone(), jetstream_config, cluster).await?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            client: Arc::new(client),
+            jetstream,
+            codec,
+        })
+    }
+
+    async fn connect_options(conf: &NatsConfig) -> Result<ConnectOptions, AnyhowError> {
         let mut options = ConnectOptions::new();
         if let Some(max_reconnects) = conf.max_reconnects {
             options = options.max_reconnects(max_reconnects as usize);
         }
+        if let Some(reconnect_wait) = conf.reconnect_wait {
+            options = options.reconnect_delay_callback(move |attempts| {
+                reconnect_delay(reconnect_wait, attempts)
+            });
+        }
+        if let Some(client_id) = &conf.client_id {
+            options = options.name(client_id);
+        }
 
-        let client = options
-            .connect(&conf.url)
+        let methods = [
+            conf.token.is_some(),
+            conf.user.is_some() || conf.password.is_some(),
+            conf.nkey.is_some(),
+            conf.credentials_file.is_some(),
+        ];
+        ensure!(
+            methods.iter().filter(|&&configured| configured).count() <= 1,
+            "NATS token, user, nkey and credentials_file are mutually exclusive"
+        );
+        if let Some(token) = &conf.token {
+            options = options.token(token.clone());
+        }
+        match (&conf.user, &conf.password) {
+            (Some(user), Some(password)) => {
+                options = options.user_and_password(user.clone(), password.clone())
+            }
+            (None, None) => {}
+            _ => bail!("NATS user and password have to be set together"),
+        }
+        if let Some(nkey) = &conf.nkey {
+            options = options.nkey(nkey.clone());
+        }
+        if let Some(credentials_file) = &conf.credentials_file {
+            options = options
+                .credentials_file(credentials_file)
+                .await
+                .with_context(|| format!("Failed to read NATS credentials {}", credentials_file))?;
+        }
+
+        if let Some(tls) = &conf.tls {
+            options = options.require_tls(true);
+            if let Some(ca_file) = &tls.ca_file {
+                options = options.add_root_certificates(ca_file.into());
+            }
+            match (&tls.cert_file, &tls.key_file) {
+                (Some(cert_file), Some(key_file)) => {
+                    options = options.add_client_certificate(cert_file.into(), key_file.into())
+                }
+                (None, None) => {}
+                _ => bail!("NATS TLS cert_file and key_file have to be set together"),
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl JetStream {
+    async fn new(
+        client: Client,
+        conf: &JetStreamConfig,
+        cluster: &str,
+    ) -> Result<Self, AnyhowError> {
+        let context = jetstream::new(client);
+        let name = conf
+            .stream
+            .clone()
+            .unwrap_or_else(|| format!("pluto_{}", token(cluster)));
+        let subjects: Vec<String> = PubSubTopics::state_patterns()
+            .iter()
+            .map(|pattern| pattern.in_cluster(cluster).to_string())
+            .collect();
+        // the last message of a subject is the latest state of whoever
+        // publishes on it, older ones are of no use. An existing stream is
+        // used as it is, apart from its subjects
+        let mut stream = context
+            .get_or_create_stream(jetstream::stream::Config {
+                name: name.clone(),
+                subjects: subjects.clone(),
+                max_messages_per_subject: 1,
+                max_age: conf.max_age,
+                num_replicas: conf.replicas.unwrap_or(1),
+                ..Default::default()
+            })
             .await
-            .context("Failed to connect to NATS server")?;
+            .with_context(|| format!("Failed to create JetStream stream {}", name))?;
+
+        let mut config = stream.cached_info().config.clone();
+        if config.subjects != subjects {
+            info!(
+                "limiting subjects of JetStream stream {} to the state subjects",
+                name
+            );
+            config.subjects = subjects.clone();
+            context
+                .update_stream(config)
+                .await
+                .with_context(|| format!("Failed to update JetStream stream {}", name))?;
+            stream = context
+                .get_stream(&name)
+                .await
+                .with_context(|| format!("Failed to get JetStream stream {}", name))?;
+        }
         Ok(Self {
-            client: Arc::new(client),
+            context,
+            stream,
+            subjects,
         })
     }
+
+    // whether the stream retains what is published on `topic`, for a
+    // pattern whether it only matches subjects the stream retains
+    fn retains(&self, topic: &Topic) -> bool {
+        self.subjects
+            .iter()
+            .any(|subject| matches(subject, topic.as_str()))
+    }
+
+    async fn consumer(
+        &self,
+        topic: &Topic,
+        deliver_policy: DeliverPolicy,
+    ) -> Result<Consumer<OrderedConfig>, AnyhowError> {
+        let consumer = self
+            .stream
+            .create_consumer(OrderedConfig {
+                filter_subject: topic.to_string(),
+                deliver_policy,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        Ok(consumer)
+    }
+
+    async fn consume(
+        &self,
+        topic: &Topic,
+        deliver_policy: DeliverPolicy,
+    ) -> Result<Ordered, AnyhowError> {
+        let messages = self
+            .consumer(topic, deliver_policy)
+            .await?
+            .messages()
+            .await
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        Ok(messages)
+    }
+}
+
+// doubles the wait on every failed attempt, up to MAX_RECONNECT_WAIT unless
+// the configured wait is longer than that already
+fn reconnect_delay(wait: Duration, attempts: usize) -> Duration {
+    let doublings = attempts.saturating_sub(1).min(16) as u32;
+    wait.saturating_mul(1 << doublings)
+        .min(MAX_RECONNECT_WAIT.max(wait))
+}
+
+// passes a received payload on, false once the subscriber is gone
+async fn deliver(tx: &mpsc::Sender<Delivery>, codec: Codec, subject: &str, payload: &[u8]) -> bool {
+    tx.send(decode_delivery(codec, subject, payload))
+        .await
+        .is_ok()
 }
 
 #[async_trait]
 impl PubSub for NatsPubSub {
-    async fn publish(&self, topic: PubSubTopics, message: Message) -> Result<(), AnyhowError> {
-        let payload =
-            serde_json::to_vec(&message).map_err(|e| Error::SerializationError(e.to_string()))?;
-        self.client
-            .publish(topic.as_str(), payload.into())
-            .await
-            .map_err(|e| Error::PublishError(e.to_string()))?;
+    async fn publish(&self, topic: Topic, message: Envelope) -> Result<(), AnyhowError> {
+        let payload = self.codec.encode(&message)?;
+        match self
+            .jetstream
+            .as_ref()
+            .filter(|jetstream| jetstream.retains(&topic))
+        {
+            // waits for the ack, so the message is retained once this returns
+            Some(jetstream) => {
+                jetstream
+                    .context
+                    .publish(topic.to_string(), payload.into())
+                    .await
+                    .map_err(|e| Error::PublishError(e.to_string()))?
+                    .await
+                    .map_err(|e| Error::PublishError(e.to_string()))?;
+            }
+            None => {
+                self.client
+                    .publish(topic.to_string(), payload.into())
+                    .await
+                    .map_err(|e| Error::PublishError(e.to_string()))?;
+            }
+        }
         Ok(())
     }
 
-    async fn subscribe(&self, topic: PubSubTopics) -> Result<mpsc::Receiver<Message>, AnyhowError> {
-        let mut subscription = self
-            .client
-            .subscribe(topic.as_str())
+    async fn subscribe(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, AnyhowError> {
+        let (tx, rx) = mpsc::channel(100);
+        let codec = self.codec;
+
+        let retained = self
+            .jetstream
+            .as_ref()
+            .filter(|jetstream| jetstream.retains(&topic));
+        let Some(jetstream) = retained else {
+            let mut subscription = self
+                .client
+                .subscribe(topic.to_string())
+                .await
+                .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+            tokio::spawn(async move {
+                while let Some(msg) = subscription.next().await {
+                    // requests made with `request` carry the subject their
+                    // reply is expected on
+                    let delivery =
+                        decode_delivery(codec, &msg.subject, &msg.payload).map(|mut envelope| {
+                            if let Some(reply) = &msg.reply {
+                                envelope.reply_to = Some(reply.to_string());
+                            }
+                            envelope
+                        });
+                    if tx.send(delivery).await.is_err() {
+                        return;
+                    }
+                }
+                warn!("NATS subscription to {} closed", topic);
+            });
+            return Ok(rx);
+        };
+
+        // an ordered consumer picks up where it left off after a reconnect,
+        // with the latest message of every subject published in the
+        // meantime. Older ones were replaced by the time they are fetched
+        let mut messages = jetstream.consume(&topic, DeliverPolicy::New).await?;
+        tokio::spawn(async move {
+            while let Some(msg) = messages.next().await {
+                match msg {
+                    Ok(msg) => {
+                        if !deliver(&tx, codec, &msg.subject, &msg.payload).await {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("failed to receive from JetStream on {}: {}", topic, e),
+                }
+            }
+            warn!("JetStream subscription to {} closed", topic);
+        });
+        Ok(rx)
+    }
+
+    async fn replay(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, AnyhowError> {
+        let (tx, rx) = mpsc::channel(100);
+        let retained = self
+            .jetstream
+            .as_ref()
+            .filter(|jetstream| jetstream.retains(&topic));
+        let Some(jetstream) = retained else {
+            return Ok(rx);
+        };
+
+        let consumer = jetstream
+            .consumer(&topic, DeliverPolicy::LastPerSubject)
+            .await?;
+        if consumer.cached_info().num_pending == 0 {
+            return Ok(rx);
+        }
+        let mut messages = consumer
+            .messages()
             .await
             .map_err(|e| Error::SubscriptionError(e.to_string()))?;
-        let (tx, rx) = mpsc::channel(100);
+        let codec = self.codec;
         tokio::spawn(async move {
-            while let Some(msg) = subscription.next().await {
-                if let Ok(message) = serde_json::from_slice::<Message>(&msg.payload) {
-                    if tx.send(message).await.is_err() {
+            while let Some(msg) = messages.next().await {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        warn!("failed to replay JetStream messages on {}: {}", topic, e);
                         break;
                     }
+                };
+                // pending counts what is left of the replay after this one
+                let done = msg.info().map_or(true, |info| info.pending == 0);
+                if !deliver(&tx, codec, &msg.subject, &msg.payload).await || done {
+                    break;
                 }
             }
+            debug!("replayed JetStream messages on {}", topic);
         });
-
         Ok(rx)
     }
+
+    // requests and replies stay on core NATS in JetStream mode too
+    fn supports_request(&self) -> bool {
+        true
+    }
+
+    async fn request(
+        &self,
+        topic: Topic,
+        envelope: Envelope,
+        timeout: Duration,
+    ) -> Result<Delivery, AnyhowError> {
+        let payload = self.codec.encode(&envelope)?;
+        let request = async_nats::Request::new()
+            .payload(payload.into())
+            .timeout(Some(timeout));
+        let reply = self
+            .client
+            .send_request(topic.to_string(), request)
+            .await
+            .map_err(|e| Error::RequestError(format!("{} on {}", e, topic)))?;
+        Ok(decode_delivery(self.codec, &reply.subject, &reply.payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pubsub::{Message, Sender};
+    use crate::transport::topics::{PubSubTopics, Target};
+
+    fn conf() -> NatsConfig {
+        NatsConfig {
+            url: "nats://localhost:4222".to_string(),
+            cluster_id: None,
+            client_id: Some("gateway1".to_string()),
+            max_reconnects: None,
+            reconnect_wait: Some(Duration::from_secs(2)),
+            token: None,
+            user: None,
+            password: None,
+            nkey: None,
+            credentials_file: None,
+            tls: None,
+            jetstream: None,
+        }
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        let wait = Duration::from_secs(2);
+        assert_eq!(reconnect_delay(wait, 1), wait);
+        assert_eq!(reconnect_delay(wait, 3), Duration::from_secs(8));
+        assert_eq!(reconnect_delay(wait, 100), MAX_RECONNECT_WAIT);
+        assert_eq!(
+            reconnect_delay(Duration::from_secs(60), 5),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_options() {
+        let mut token = conf();
+        token.token = Some("secret".to_string());
+        assert!(NatsPubSub::connect_options(&token).await.is_ok());
+
+        let mut conflicting = token.clone();
+        conflicting.user = Some("pluto".to_string());
+        conflicting.password = Some("secret".to_string());
+        assert!(NatsPubSub::connect_options(&conflicting).await.is_err());
+
+        let mut no_password = conf();
+        no_password.user = Some("pluto".to_string());
+        assert!(NatsPubSub::connect_options(&no_password).await.is_err());
+
+        let mut missing_creds = conf();
+        missing_creds.credentials_file = Some("/nonexistent/pluto.creds".to_string());
+        assert!(NatsPubSub::connect_options(&missing_creds).await.is_err());
+    }
+
+    // needs a local nats-server with JetStream enabled (`nats-server -js`),
+    // run it with `cargo test -- --ignored` and point NATS_URL at it when it
+    // isn't on the default port
+    #[tokio::test]
+    #[ignore]
+    async fn test_jetstream_replay() {
+        let cluster = format!("test-{}", std::process::id());
+        let mut conf = conf();
+        conf.url = std::env::var("NATS_URL").unwrap_or_else(|_| conf.url.clone());
+        conf.jetstream = Some(JetStreamConfig {
+            stream: None,
+            max_age: Duration::from_secs(60),
+            replicas: None,
+        });
+        let pubsub = NatsPubSub::new(conf, &cluster, Codec::default())
+            .await
+            .unwrap();
+
+        let subject = |gateway_id| {
+            PubSubTopics::PublishGatewayHeartbeat
+                .gateway_subject("local", gateway_id)
+                .in_cluster(&cluster)
+        };
+        for (gateway_id, message) in [
+            ("gateway1", Message::Ping),
+            ("gateway2", Message::Ping),
+            ("gateway1", Message::Pong),
+        ] {
+            let envelope = Envelope::new(&cluster, Sender::gateway(gateway_id), 0, message);
+            pubsub.publish(subject(gateway_id), envelope).await.unwrap();
+        }
+
+        // only the latest of every subject, then the replay ends
+        let mut rx = pubsub
+            .replay(
+                PubSubTopics::PublishGatewayHeartbeat
+                    .gateway_pattern(Target::All)
+                    .in_cluster(&cluster),
+            )
+            .await
+            .unwrap();
+        let mut replayed = Vec::new();
+        while let Some(delivery) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+        {
+            let envelope = delivery.unwrap();
+            replayed.push((envelope.sender.id, envelope.message));
+        }
+        assert_eq!(replayed.len(), 2);
+        assert!(replayed
+            .iter()
+            .any(|(id, message)| id == "gateway1" && matches!(message, Message::Pong)));
+    }
+
+    // needs a local nats-server, like the test above
+    #[tokio::test]
+    #[ignore]
+    async fn test_native_request() {
+        let mut conf = conf();
+        conf.url = std::env::var("NATS_URL").unwrap_or_else(|_| conf.url.clone());
+        let pubsub = NatsPubSub::new(conf, "default", Codec::default())
+            .await
+            .unwrap();
+        assert!(pubsub.supports_request());
+
+        let topic = Topic::gateway_requests(&format!("test-{}", std::process::id()));
+        let mut requests = pubsub.subscribe(topic.clone()).await.unwrap();
+        let responder = pubsub.clone();
+        tokio::spawn(async move {
+            let request = requests.recv().await.unwrap().unwrap();
+            let mut reply = Envelope::new("default", Sender::gateway("gateway1"), 0, Message::Pong);
+            reply.correlation_id = request.correlation_id;
+            let reply_to = Topic::from_subject(request.reply_to.as_deref().unwrap());
+            responder.publish(reply_to, reply).await.unwrap();
+        });
+
+        let mut request = Envelope::new("default", Sender::orbit("orbit"), 7, Message::Ping);
+        request.correlation_id = Some(7);
+        let reply = pubsub
+            .request(topic, request, Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(reply.message, Message::Pong));
+        assert_eq!(reply.correlation_id, Some(7));
+    }
 }
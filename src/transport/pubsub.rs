@@ -1,13 +1,24 @@
-use crate::common::types::GatewayLatencyStats;
-use anyhow::Error;
+use crate::common::types::{
+    GatewayFailoverEvent, GatewayHeartbeat, GatewayLatencyStats, GatewayMeshStats,
+    GatewayStatusUpdate, StorePaths, StoreQuery,
+};
+use anyhow::{anyhow, bail, Error};
 use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, SelectAll};
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::mpsc;
-use tracing::debug;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+use tracing::{debug, error, warn};
 
-use super::topics::PubSubTopics;
+use super::codec::Codec;
+use super::inbox::{inbox, Inbox, InboxSender, Pushed};
+use super::topics::Topic;
+use crate::common::types::BackpressureConfig;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LatencyStats {
@@ -20,55 +31,705 @@ pub struct LatencyStats {
 pub enum Message {
     Data(String),
     GatewayLatencyStats(GatewayLatencyStats),
+    GatewayMeshStats(GatewayMeshStats),
+    GatewayHeartbeat(GatewayHeartbeat),
+    GatewayStatus(GatewayStatusUpdate),
+    GatewayFailover(GatewayFailoverEvent),
+    DeadLetter(DeadLetter),
     Ping,
     Pong,
+    // asks orbit for the latest stats of every live gateway
+    SnapshotRequest,
+    Snapshot(Vec<Envelope>),
+    StoreQuery(StoreQuery),
+    StorePaths(StorePaths),
+    // the reply to a request that could not be answered
+    RequestFailed(String),
+}
+
+// a message that could not be decoded, as it was received on `subject`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeadLetter {
+    pub subject: String,
+    pub error: String,
+    pub payload: Vec<u8>,
+}
+
+// bumped on incompatible changes to the envelope or the messages
+pub const ENVELOPE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Orbit,
+    Gateway,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Sender {
+    pub id: String,
+    pub role: Role,
+}
+
+impl Sender {
+    pub fn orbit(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            role: Role::Orbit,
+        }
+    }
+
+    pub fn gateway(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            role: Role::Gateway,
+        }
+    }
+}
+
+// What goes over the wire, the message along with who published it, when
+// and in which cluster
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Envelope {
+    pub version: u32,
+    pub cluster: String,
+    pub sender: Sender,
+    // increases with every message of the sender
+    pub sequence: u64,
+    pub sent_at: SystemTime,
+    pub message: Message,
+    // set on requests and echoed on their replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<u64>,
+    // the full subject a request expects its reply on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+}
+
+impl Envelope {
+    pub fn new(cluster: &str, sender: Sender, sequence: u64, message: Message) -> Self {
+        Self {
+            version: ENVELOPE_VERSION,
+            cluster: cluster.to_string(),
+            sender,
+            sequence,
+            sent_at: SystemTime::now(),
+            message,
+            correlation_id: None,
+            reply_to: None,
+        }
+    }
+}
+
+// a payload a backend received but could not decode, passed on so that the
+// manager can count and dead-letter it
+#[derive(Debug, Clone)]
+pub struct Undecodable {
+    pub subject: String,
+    pub payload: Vec<u8>,
+    pub error: String,
+}
+
+// what a subscription yields
+pub type Delivery = Result<Envelope, Undecodable>;
+
+pub(crate) fn decode_delivery(codec: Codec, subject: &str, payload: &[u8]) -> Delivery {
+    codec.decode(payload).map_err(|e| Undecodable {
+        subject: subject.to_string(),
+        payload: payload.to_vec(),
+        error: e.to_string(),
+    })
 }
 
 #[async_trait]
-pub trait PubSub: Clone + Send + Sync + 'static {
-    async fn publish(&self, topic: PubSubTopics, message: Message) -> Result<(), Error>;
-    async fn subscribe(&self, topic: PubSubTopics) -> Result<mpsc::Receiver<Message>, Error>;
+pub trait PubSub: Debug + Send + Sync + 'static {
+    async fn publish(&self, topic: Topic, envelope: Envelope) -> Result<(), Error>;
+    // the receiver closes when the subscription is lost
+    async fn subscribe(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, Error>;
+
+    // the latest message retained on each subject matching `topic`, the
+    // receiver closes once they are all delivered. Nothing is retained
+    // unless the backend is configured to
+    async fn replay(&self, _topic: Topic) -> Result<mpsc::Receiver<Delivery>, Error> {
+        let (_, rx) = mpsc::channel(1);
+        Ok(rx)
+    }
+
+    // whether the backend routes replies to requests itself, otherwise the
+    // manager emulates request/reply on top of publish and subscribe
+    fn supports_request(&self) -> bool {
+        false
+    }
+
+    // publishes the request and waits up to `timeout` for the first reply
+    async fn request(
+        &self,
+        topic: Topic,
+        _envelope: Envelope,
+        _timeout: Duration,
+    ) -> Result<Delivery, Error> {
+        bail!("no request/reply support for {}", topic)
+    }
 }
 
+// messages that never reached a subscriber
+#[derive(Debug, Default)]
+struct Counters {
+    undecodable: AtomicU64,
+    rejected: AtomicU64,
+    // dropped for a subscriber that fell behind
+    overflowed: AtomicU64,
+}
+
+// Scopes everything published and subscribed to a single cluster, so
+// clusters sharing a broker don't see each other's messages, and stamps what
+// is published with the sender
 #[derive(Debug, Clone)]
-pub struct PubSubManager<T: PubSub> {
-    inner: Arc<T>,
+pub struct PubSubManager {
+    inner: Arc<dyn PubSub>,
+    cluster: String,
+    sender: Sender,
+    // seeded from the clock so that it keeps increasing across restarts
+    sequence: Arc<AtomicU64>,
+    counters: Arc<Counters>,
+    backpressure: BackpressureConfig,
+    // emulated requests awaiting their reply, by correlation id
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Envelope>>>>,
+    // whether replies to emulated requests are subscribed to, made on the
+    // first request and again on the next one once the subscription is lost
+    replies: Arc<AsyncMutex<bool>>,
 }
 
-impl<T: PubSub> PubSubManager<T> {
-    pub fn new(inner: T) -> Self {
+impl PubSubManager {
+    pub fn new(inner: Arc<dyn PubSub>, cluster: &str, sender: Sender) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
         Self {
-            inner: Arc::new(inner),
+            inner,
+            cluster: cluster.to_string(),
+            sender,
+            sequence: Arc::new(AtomicU64::new(seed)),
+            counters: Arc::new(Counters::default()),
+            backpressure: BackpressureConfig::default(),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            replies: Arc::new(AsyncMutex::new(false)),
         }
     }
 
-    pub async fn broadcast(&self, topics: &[PubSubTopics], message: Message) -> Result<(), Error> {
+    // how much every subscription buffers and what gives once it is full
+    pub fn with_backpressure(mut self, conf: BackpressureConfig) -> Self {
+        self.backpressure = conf;
+        self
+    }
+
+    pub fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    // received payloads that failed to decode
+    pub fn undecodable_count(&self) -> u64 {
+        self.counters.undecodable.load(Ordering::Relaxed)
+    }
+
+    // envelopes dropped for their version or cluster
+    pub fn rejected_count(&self) -> u64 {
+        self.counters.rejected.load(Ordering::Relaxed)
+    }
+
+    // envelopes dropped because a subscriber fell behind
+    pub fn overflow_count(&self) -> u64 {
+        self.counters.overflowed.load(Ordering::Relaxed)
+    }
+
+    fn envelope(&self, message: Message) -> Envelope {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        Envelope::new(&self.cluster, self.sender.clone(), sequence, message)
+    }
+
+    pub async fn broadcast(&self, topics: &[Topic], message: Message) -> Result<(), Error> {
+        let envelope = self.envelope(message);
+        self.forward(topics, envelope).await
+    }
+
+    // sends `message` to whoever answers requests on `topic` and waits up to
+    // `timeout` for the reply. A `RequestFailed` reply is returned as an error
+    pub async fn request(
+        &self,
+        topic: &Topic,
+        message: Message,
+        timeout: Duration,
+    ) -> Result<Envelope, Error> {
+        let mut envelope = self.envelope(message);
+        let correlation_id = envelope.sequence;
+        envelope.correlation_id = Some(correlation_id);
+        let topic = topic.in_cluster(&self.cluster);
+        debug!("requesting {:?} on {}", envelope.message, topic);
+
+        let reply = if self.inner.supports_request() {
+            match self.inner.request(topic.clone(), envelope, timeout).await? {
+                Ok(reply) => reply,
+                Err(undecodable) => {
+                    let error = undecodable.error.clone();
+                    self.dead_letter(undecodable).await;
+                    bail!("undecodable reply to request on {}: {}", topic, error)
+                }
+            }
+        } else {
+            self.emulate_request(&topic, envelope, timeout).await?
+        };
+
+        if !self.admits(&reply) {
+            bail!("reply to request on {} was dropped", topic);
+        }
+        match reply.message {
+            Message::RequestFailed(error) => {
+                bail!(
+                    "request on {} failed at {}: {}",
+                    topic,
+                    reply.sender.id,
+                    error
+                )
+            }
+            _ => Ok(reply),
+        }
+    }
+
+    // request/reply on top of publish and subscribe, the reply comes back
+    // on this sender's own reply subject
+    async fn emulate_request(
+        &self,
+        topic: &Topic,
+        mut envelope: Envelope,
+        timeout: Duration,
+    ) -> Result<Envelope, Error> {
+        let correlation_id = envelope.sequence;
+        let (tx, rx) = oneshot::channel();
+        {
+            // held while pending, so a lost subscription fails the request
+            // rather than leaving it to time out
+            let mut subscribed = self.replies.lock().await;
+            if !*subscribed {
+                self.receive_replies().await?;
+                *subscribed = true;
+            }
+            self.pending.lock().unwrap().insert(correlation_id, tx);
+        }
+        envelope.reply_to = Some(
+            Topic::replies(&self.sender)
+                .in_cluster(&self.cluster)
+                .to_string(),
+        );
+
+        let reply = async {
+            self.inner.publish(topic.clone(), envelope).await?;
+            tokio::time::timeout(timeout, rx)
+                .await
+                .map_err(|_| anyhow!("no reply to request on {} within {:?}", topic, timeout))?
+                .map_err(|_| anyhow!("replies to requests on {} lost", topic))
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&correlation_id);
+        reply
+    }
+
+    // hands replies to the requests awaiting them
+    async fn receive_replies(&self) -> Result<(), Error> {
+        let mut replies = self
+            .subscribe_to_topics(&[Topic::replies(&self.sender)])
+            .await?;
+        let pending = Arc::clone(&self.pending);
+        let subscribed = Arc::clone(&self.replies);
+        tokio::spawn(async move {
+            while let Some(reply) = replies.recv().await {
+                let tx = reply
+                    .correlation_id
+                    .and_then(|id| pending.lock().unwrap().remove(&id));
+                match tx {
+                    Some(tx) => {
+                        let _ = tx.send(reply);
+                    }
+                    None => debug!("dropping late or unexpected reply from {}", reply.sender.id),
+                }
+            }
+            // fails whatever still awaits a reply, the next request
+            // subscribes again
+            error!("subscription to replies lost");
+            let mut subscribed = subscribed.lock().await;
+            *subscribed = false;
+            pending.lock().unwrap().clear();
+        });
+        Ok(())
+    }
+
+    // answers `request` on the subject it expects its reply on
+    pub async fn reply(&self, request: &Envelope, message: Message) -> Result<(), Error> {
+        let Some(reply_to) = &request.reply_to else {
+            bail!("message from {} expects no reply", request.sender.id);
+        };
+        let mut envelope = self.envelope(message);
+        envelope.correlation_id = request.correlation_id;
+        self.inner
+            .publish(Topic::from_subject(reply_to), envelope)
+            .await
+    }
+
+    // round trip to whoever answers requests on `topic`
+    pub async fn ping(&self, topic: &Topic, timeout: Duration) -> Result<Duration, Error> {
+        let started = Instant::now();
+        let reply = self.request(topic, Message::Ping, timeout).await?;
+        match reply.message {
+            Message::Pong => Ok(started.elapsed()),
+            other => bail!("expected a pong from {}, got {:?}", reply.sender.id, other),
+        }
+    }
+
+    // publishes an envelope as is, so relayed messages keep their sender
+    pub async fn forward(&self, topics: &[Topic], envelope: Envelope) -> Result<(), Error> {
         debug!("broadcasting message to topics: {:?}", topics);
         for topic in topics {
-            self.inner.publish(topic.clone(), message.clone()).await?;
+            self.inner
+                .publish(topic.in_cluster(&self.cluster), envelope.clone())
+                .await?;
         }
         Ok(())
     }
 
-    pub async fn subscribe_to_topics(
-        &self,
-        topics: &[PubSubTopics],
-    ) -> Result<mpsc::Receiver<Message>, Error> {
-        let (tx, rx) = mpsc::channel(100);
-
+    // The receiver closes as soon as the subscription to any of the topics
+    // is lost, for the subscriber to bail out rather than miss messages
+    pub async fn subscribe_to_topics(&self, topics: &[Topic]) -> Result<Inbox, Error> {
+        let mut receivers = Vec::new();
         for topic in topics {
-            let mut receiver = self.inner.subscribe(topic.clone()).await?;
-            let tx = tx.clone();
+            let topic = topic.in_cluster(&self.cluster);
+            receivers.push((topic.clone(), self.inner.subscribe(topic).await?));
+        }
 
-            tokio::spawn(async move {
-                while let Some(message) = receiver.recv().await {
-                    if tx.send(message).await.is_err() {
-                        break;
+        let (tx, rx) = inbox(&self.backpressure);
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut deliveries = merge(receivers);
+            while let Some(delivery) = deliveries.next().await {
+                match delivery {
+                    Ok(delivery) => {
+                        if !manager.accept(delivery, &tx).await {
+                            debug!("subscriber went away");
+                            return;
+                        }
+                    }
+                    Err(topic) => {
+                        error!("subscription to {} lost", topic);
+                        return;
                     }
                 }
-            });
+            }
+        });
+
+        Ok(rx)
+    }
+
+    // the latest retained state of the topics, e.g. to catch up on startup.
+    // The receiver closes once the replay is done
+    pub async fn replay(&self, topics: &[Topic]) -> Result<Inbox, Error> {
+        let mut receivers = Vec::new();
+        for topic in topics {
+            let topic = topic.in_cluster(&self.cluster);
+            receivers.push((topic.clone(), self.inner.replay(topic).await?));
         }
 
+        let (tx, rx) = inbox(&self.backpressure);
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut deliveries = merge(receivers);
+            while let Some(delivery) = deliveries.next().await {
+                // a topic that is done replaying ends the same way
+                if let Ok(delivery) = delivery {
+                    if !manager.accept(delivery, &tx).await {
+                        return;
+                    }
+                }
+            }
+        });
+
         Ok(rx)
     }
+
+    // passes on the envelopes meant for this cluster and version, false once
+    // the subscriber is gone
+    async fn accept(&self, delivery: Delivery, tx: &InboxSender) -> bool {
+        let envelope = match delivery {
+            Ok(envelope) => envelope,
+            Err(undecodable) => {
+                self.dead_letter(undecodable).await;
+                return true;
+            }
+        };
+        if !self.admits(&envelope) {
+            return true;
+        }
+        match tx.push(envelope).await {
+            Pushed::Queued => true,
+            Pushed::Coalesced => {
+                debug!("replaced a queued update with a newer one");
+                true
+            }
+            Pushed::Overflowed => {
+                let count = self.counters.overflowed.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!(
+                    "subscriber fell behind, dropped the oldest message ({} so far)",
+                    count
+                );
+                true
+            }
+            Pushed::Closed => false,
+        }
+    }
+
+    // whether the envelope is meant for this cluster and version
+    fn admits(&self, envelope: &Envelope) -> bool {
+        if envelope.version != ENVELOPE_VERSION {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "dropping message from {} with envelope version {}, expected {}",
+                envelope.sender.id, envelope.version, ENVELOPE_VERSION
+            );
+            return false;
+        }
+        if envelope.cluster != self.cluster {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "dropping message from cluster {}, expected {}",
+                envelope.cluster, self.cluster
+            );
+            return false;
+        }
+        true
+    }
+
+    // republishes what could not be decoded for someone to look into it
+    async fn dead_letter(&self, undecodable: Undecodable) {
+        let count = self.counters.undecodable.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "failed to decode message on {}: {} ({} so far)",
+            undecodable.subject, undecodable.error, count
+        );
+        let subject = undecodable.subject.clone();
+        let message = Message::DeadLetter(DeadLetter {
+            subject: undecodable.subject,
+            error: undecodable.error,
+            payload: undecodable.payload,
+        });
+        if let Err(e) = self
+            .broadcast(&[Topic::dead_letter(&self.sender)], message)
+            .await
+        {
+            warn!("failed to dead-letter message from {}: {}", subject, e);
+        }
+    }
+}
+
+// one stream of what the receivers yield, each followed by its topic once
+// it closes
+fn merge(
+    receivers: Vec<(Topic, mpsc::Receiver<Delivery>)>,
+) -> SelectAll<BoxStream<'static, Result<Delivery, Topic>>> {
+    stream::select_all(receivers.into_iter().map(|(topic, receiver)| {
+        stream::unfold(receiver, |mut receiver| async move {
+            let delivery = receiver.recv().await?;
+            Some((Ok(delivery), receiver))
+        })
+        .chain(stream::once(async move { Err(topic) }))
+        .boxed()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory::{MemoryBroker, MemoryPubSub};
+    use crate::transport::topics::{PubSubTopics, Target};
+
+    fn manager(pubsub: &Arc<MemoryPubSub>, cluster: &str, sender: Sender) -> PubSubManager {
+        PubSubManager::new(pubsub.clone(), cluster, sender)
+    }
+
+    #[tokio::test]
+    async fn test_clusters_are_isolated() {
+        let pubsub = Arc::new(MemoryPubSub::new(MemoryBroker::new()));
+        let staging = manager(&pubsub, "staging", Sender::gateway("gateway1"));
+        let production = manager(&pubsub, "production", Sender::gateway("gateway1"));
+
+        let topic = PubSubTopics::PublishGatewayHeartbeat.gateway_subject("local", "gateway1");
+        let pattern = PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::All);
+        let mut staging_rx = staging
+            .subscribe_to_topics(std::slice::from_ref(&pattern))
+            .await
+            .unwrap();
+        let mut production_rx = production.subscribe_to_topics(&[pattern]).await.unwrap();
+
+        staging
+            .broadcast(std::slice::from_ref(&topic), Message::Ping)
+            .await
+            .unwrap();
+        let envelope = staging_rx.recv().await.unwrap();
+        assert!(matches!(envelope.message, Message::Ping));
+
+        // even on production's subjects, a message of another cluster is dropped
+        pubsub
+            .publish(topic.in_cluster("production"), envelope)
+            .await
+            .unwrap();
+        production.broadcast(&[topic], Message::Pong).await.unwrap();
+        let envelope = production_rx.recv().await.unwrap();
+        assert!(matches!(envelope.message, Message::Pong));
+    }
+
+    #[tokio::test]
+    async fn test_envelope_metadata() {
+        let pubsub = Arc::new(MemoryPubSub::new(MemoryBroker::new()));
+        let gateway = manager(&pubsub, "default", Sender::gateway("gateway1"));
+        let orbit = manager(&pubsub, "default", Sender::orbit("orbit"));
+
+        let topic = PubSubTopics::GatewayToOrbitStats.gateway_subject("local", "gateway1");
+        let pattern = PubSubTopics::GatewayToOrbitStats.gateway_pattern(Target::All);
+        let mut rx = orbit.subscribe_to_topics(&[pattern]).await.unwrap();
+
+        for _ in 0..2 {
+            gateway
+                .broadcast(std::slice::from_ref(&topic), Message::Ping)
+                .await
+                .unwrap();
+        }
+        let first = rx.recv().await.unwrap();
+        let second = rx.recv().await.unwrap();
+        assert_eq!(first.version, ENVELOPE_VERSION);
+        assert_eq!(first.sender, Sender::gateway("gateway1"));
+        assert!(second.sequence > first.sequence);
+        assert!(second.sent_at >= first.sent_at);
+
+        // forwarding keeps the original sender, other versions are dropped
+        let mut unsupported = first.clone();
+        unsupported.version = ENVELOPE_VERSION + 1;
+        orbit
+            .forward(std::slice::from_ref(&topic), unsupported)
+            .await
+            .unwrap();
+        orbit.forward(&[topic], first.clone()).await.unwrap();
+        let forwarded = rx.recv().await.unwrap();
+        assert_eq!(forwarded.sender, Sender::gateway("gateway1"));
+        assert_eq!(forwarded.sequence, first.sequence);
+    }
+
+    #[tokio::test]
+    async fn test_request_reply() {
+        let pubsub = Arc::new(MemoryPubSub::new(MemoryBroker::new()));
+        let gateway = manager(&pubsub, "default", Sender::gateway("gateway1"));
+        let orbit = manager(&pubsub, "default", Sender::orbit("orbit"));
+
+        let mut requests = gateway
+            .subscribe_to_topics(&[Topic::gateway_requests("gateway1")])
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                let reply = match request.message {
+                    Message::Ping => Message::Pong,
+                    _ => Message::RequestFailed("unsupported".to_string()),
+                };
+                gateway.reply(&request, reply).await.unwrap();
+            }
+        });
+
+        // concurrent requests each get their own reply
+        let timeout = Duration::from_secs(1);
+        let topic = Topic::gateway_requests("gateway1");
+        let (ping, failed) = tokio::join!(
+            orbit.ping(&topic, timeout),
+            orbit.request(&topic, Message::SnapshotRequest, timeout)
+        );
+        assert!(ping.is_ok());
+        assert!(failed.unwrap_err().to_string().contains("unsupported"));
+
+        // nobody answers for gateway2
+        let unanswered = orbit
+            .request(
+                &Topic::gateway_requests("gateway2"),
+                Message::Ping,
+                Duration::from_millis(50),
+            )
+            .await;
+        assert!(unanswered.is_err());
+        assert!(orbit.pending.lock().unwrap().is_empty());
+    }
+
+    // hands every subscriber a single undecodable payload, then loses the
+    // subscription
+    #[derive(Debug, Default)]
+    struct Broken {
+        published: std::sync::Mutex<Vec<(Topic, Envelope)>>,
+    }
+
+    #[async_trait]
+    impl PubSub for Broken {
+        async fn publish(&self, topic: Topic, envelope: Envelope) -> Result<(), Error> {
+            self.published.lock().unwrap().push((topic, envelope));
+            Ok(())
+        }
+
+        async fn subscribe(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, Error> {
+            let (tx, rx) = mpsc::channel(1);
+            tx.send(decode_delivery(
+                Codec::default(),
+                topic.as_str(),
+                b"garbage",
+            ))
+            .await?;
+            Ok(rx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_fail_on_lost_replies() {
+        let broken = Arc::new(Broken::default());
+        let manager = PubSubManager::new(broken.clone(), "default", Sender::orbit("orbit"));
+
+        // fails as the subscription to replies is lost rather than timing
+        // out, and every request subscribes again
+        let timeout = Duration::from_secs(10);
+        for _ in 0..2 {
+            let started = Instant::now();
+            let failed = manager
+                .request(&Topic::gateway_requests("gateway1"), Message::Ping, timeout)
+                .await;
+            assert!(failed.unwrap_err().to_string().contains("lost"));
+            assert!(started.elapsed() < timeout);
+        }
+        assert!(manager.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_and_lost_subscriptions() {
+        let broken = Arc::new(Broken::default());
+        let manager = PubSubManager::new(broken.clone(), "default", Sender::orbit("orbit"));
+        let mut rx = manager
+            .subscribe_to_topics(&[
+                PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::All),
+                PubSubTopics::PublishGatewayFailover.gateway_pattern(Target::All),
+            ])
+            .await
+            .unwrap();
+
+        // closes with the first subscription lost
+        assert!(rx.recv().await.is_none());
+        assert!(manager.undecodable_count() >= 1);
+
+        let published = broken.published.lock().unwrap();
+        let (topic, envelope) = &published[0];
+        assert_eq!(topic.as_str(), "default.deadletter.orbit.orbit");
+        assert!(matches!(
+            &envelope.message,
+            Message::DeadLetter(DeadLetter { subject, payload, .. })
+                if subject.starts_with("default.gateway.") && payload == b"garbage"
+        ));
+    }
 }
@@ -0,0 +1,646 @@
+use crate::common::error::Error;
+use crate::common::types::QuicConfig;
+use crate::transport::codec::Codec;
+use crate::transport::pubsub::{Delivery, Envelope, PubSub, Undecodable};
+use anyhow::{ensure, Context, Error as AnyhowError};
+use async_trait::async_trait;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use quinn::rustls::RootCertStore;
+use quinn::{
+    ClientConfig, Connection, Endpoint, Incoming, ReadExactError, RecvStream, SendStream,
+    ServerConfig,
+};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, trace, warn};
+
+use super::memory::{matches, MemoryBroker, MemoryPubSub};
+use super::topics::Topic;
+
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+const OUTGOING_CAPACITY: usize = 1024;
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Brokerless transport, gateways connect to orbit over QUIC and orbit fans
+// every message out to the connections subscribed to its subject
+#[derive(Clone, Debug)]
+pub struct QuicPubSub {
+    node: Node,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Hub(Arc<Hub>),
+    Client(Arc<Client>),
+}
+
+// everything sent on the single bidirectional stream of a connection
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Frame {
+    Subscribe(String),
+    // boxed, envelopes dwarf the subscribe frames
+    Publish {
+        subject: String,
+        message: Box<Envelope>,
+    },
+}
+
+// a frame as far as it decodes without its envelope, names the subject an
+// envelope that doesn't decode was published on
+#[derive(Deserialize)]
+enum RawFrame {
+    Subscribe(IgnoredAny),
+    Publish { subject: String },
+}
+
+impl QuicPubSub {
+    pub async fn new(conf: QuicConfig, codec: Codec) -> Result<Self, AnyhowError> {
+        let node = match (&conf.listen_address, &conf.orbit_address) {
+            (Some(listen_address), _) => Node::Hub(Hub::bind(listen_address, &conf, codec).await?),
+            (None, Some(orbit_address)) => {
+                Node::Client(Client::connect(orbit_address, &conf, codec).await?)
+            }
+            (None, None) => anyhow::bail!("QUIC needs either listen_address or orbit_address"),
+        };
+        Ok(Self { node })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, AnyhowError> {
+        let endpoint = match &self.node {
+            Node::Hub(hub) => &hub.endpoint,
+            Node::Client(client) => &client.endpoint,
+        };
+        Ok(endpoint.local_addr()?)
+    }
+
+    fn broker(&self) -> &Arc<MemoryBroker> {
+        match &self.node {
+            Node::Hub(hub) => &hub.broker,
+            Node::Client(client) => &client.broker,
+        }
+    }
+}
+
+#[async_trait]
+impl PubSub for QuicPubSub {
+    async fn publish(&self, topic: Topic, message: Envelope) -> Result<(), AnyhowError> {
+        match &self.node {
+            Node::Hub(hub) => hub.dispatch(topic.as_str(), message),
+            Node::Client(client) => client
+                .outgoing
+                .send(Frame::Publish {
+                    subject: topic.as_str().to_string(),
+                    message: Box::new(message),
+                })
+                .await
+                .map_err(|e| Error::PublishError(e.to_string()))?,
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, AnyhowError> {
+        let rx = MemoryPubSub::new(Arc::clone(self.broker()))
+            .subscribe(topic.clone())
+            .await?;
+        if let Node::Client(client) = &self.node {
+            let pattern = topic.as_str().to_string();
+            let is_new = client.patterns.lock().unwrap().insert(pattern.clone());
+            if is_new {
+                client
+                    .outgoing
+                    .send(Frame::Subscribe(pattern))
+                    .await
+                    .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+            }
+        }
+        Ok(rx)
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    connection: Connection,
+    patterns: HashSet<String>,
+    outgoing: mpsc::Sender<Frame>,
+}
+
+// orbit's side, accepts gateways and relays between them and its own
+// subscribers
+#[derive(Debug)]
+struct Hub {
+    endpoint: Endpoint,
+    broker: Arc<MemoryBroker>,
+    peers: RwLock<HashMap<usize, Peer>>,
+    next_peer: AtomicUsize,
+    codec: Codec,
+}
+
+impl Hub {
+    async fn bind(
+        listen_address: &str,
+        conf: &QuicConfig,
+        codec: Codec,
+    ) -> Result<Arc<Self>, AnyhowError> {
+        let cert_file = conf
+            .cert_file
+            .as_deref()
+            .context("QUIC cert_file missing")?;
+        let key_file = conf.key_file.as_deref().context("QUIC key_file missing")?;
+        let mut server_config =
+            ServerConfig::with_single_cert(load_certs(cert_file)?, load_private_key(key_file)?)
+                .context("Invalid QUIC certificate")?;
+        server_config.transport_config(transport_config());
+
+        let address = resolve(listen_address).await?;
+        let endpoint = Endpoint::server(server_config, address)
+            .with_context(|| format!("Failed to listen on {}", listen_address))?;
+        info!("accepting QUIC connections on {}", endpoint.local_addr()?);
+
+        let hub = Arc::new(Self {
+            endpoint,
+            broker: MemoryBroker::new(),
+            peers: RwLock::new(HashMap::new()),
+            next_peer: AtomicUsize::new(0),
+            codec,
+        });
+        tokio::spawn(Arc::clone(&hub).accept());
+        Ok(hub)
+    }
+
+    async fn accept(self: Arc<Self>) {
+        while let Some(incoming) = self.endpoint.accept().await {
+            let hub = Arc::clone(&self);
+            tokio::spawn(async move {
+                let address = incoming.remote_address();
+                if let Err(e) = hub.serve(incoming).await {
+                    debug!("QUIC connection from {} closed: {}", address, e);
+                }
+            });
+        }
+    }
+
+    async fn serve(&self, incoming: Incoming) -> Result<(), AnyhowError> {
+        let connection = incoming.await?;
+        let address = connection.remote_address();
+        let (send, recv) = connection.accept_bi().await?;
+        info!("accepted QUIC connection from {}", address);
+
+        let id = self.next_peer.fetch_add(1, Ordering::Relaxed);
+        let (outgoing, rx) = mpsc::channel(OUTGOING_CAPACITY);
+        self.peers.write().unwrap().insert(
+            id,
+            Peer {
+                connection,
+                patterns: HashSet::new(),
+                outgoing,
+            },
+        );
+        tokio::spawn(write_frames(send, rx, self.codec));
+
+        let res = read_frames(recv, self.codec, |frame| match frame {
+            Ok(Frame::Subscribe(pattern)) => {
+                trace!("{} subscribed to {}", address, pattern);
+                if let Some(peer) = self.peers.write().unwrap().get_mut(&id) {
+                    peer.patterns.insert(pattern);
+                }
+            }
+            Ok(Frame::Publish { subject, message }) => self.dispatch(&subject, *message),
+            // dead-lettered by orbit's subscribers, there is no envelope to
+            // relay on to the gateways
+            Err(undecodable) => {
+                let subject = undecodable.subject.clone();
+                self.broker.deliver(&subject, Err(undecodable));
+            }
+        })
+        .await;
+
+        self.peers.write().unwrap().remove(&id);
+        res
+    }
+
+    fn dispatch(&self, subject: &str, message: Envelope) {
+        self.broker.publish(subject, message.clone());
+        for peer in self.peers.read().unwrap().values() {
+            if !peer
+                .patterns
+                .iter()
+                .any(|pattern| matches(pattern, subject))
+            {
+                continue;
+            }
+            let frame = Frame::Publish {
+                subject: subject.to_string(),
+                message: Box::new(message.clone()),
+            };
+            // a slow gateway must not hold up the others
+            if let Err(e) = peer.outgoing.try_send(frame) {
+                warn!(
+                    "dropping message on {} for {}: {}",
+                    subject,
+                    peer.connection.remote_address(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+// a gateway's side, keeps a connection to orbit and replays its
+// subscriptions whenever it reconnects
+#[derive(Debug)]
+struct Client {
+    endpoint: Endpoint,
+    broker: Arc<MemoryBroker>,
+    patterns: Mutex<HashSet<String>>,
+    outgoing: mpsc::Sender<Frame>,
+    codec: Codec,
+}
+
+impl Client {
+    async fn connect(
+        orbit_address: &str,
+        conf: &QuicConfig,
+        codec: Codec,
+    ) -> Result<Arc<Self>, AnyhowError> {
+        let mut client_config = match &conf.ca_file {
+            Some(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca_file)? {
+                    roots.add(cert).context("Invalid QUIC CA certificate")?;
+                }
+                ClientConfig::with_root_certificates(Arc::new(roots))?
+            }
+            None => ClientConfig::with_platform_verifier(),
+        };
+        client_config.transport_config(transport_config());
+
+        let address = resolve(orbit_address).await?;
+        let bind_address: SocketAddr = if address.is_ipv6() {
+            "[::]:0".parse()?
+        } else {
+            "0.0.0.0:0".parse()?
+        };
+        let mut endpoint = Endpoint::client(bind_address)?;
+        endpoint.set_default_client_config(client_config);
+
+        let server_name = conf
+            .server_name
+            .clone()
+            .unwrap_or_else(|| host(orbit_address).to_string());
+        let connection = endpoint
+            .connect(address, &server_name)?
+            .await
+            .with_context(|| format!("Failed to connect to orbit at {}", orbit_address))?;
+        info!("connected to orbit at {} over QUIC", orbit_address);
+
+        let (outgoing, rx) = mpsc::channel(OUTGOING_CAPACITY);
+        let client = Arc::new(Self {
+            endpoint,
+            broker: MemoryBroker::new(),
+            patterns: Mutex::new(HashSet::new()),
+            outgoing,
+            codec,
+        });
+        tokio::spawn(Arc::clone(&client).maintain(connection, address, server_name, rx));
+        Ok(client)
+    }
+
+    async fn maintain(
+        self: Arc<Self>,
+        mut connection: Connection,
+        address: SocketAddr,
+        server_name: String,
+        mut outgoing: mpsc::Receiver<Frame>,
+    ) {
+        loop {
+            match self.run(&connection, &mut outgoing).await {
+                Ok(()) => return,
+                Err(e) => warn!("QUIC connection to orbit lost: {}", e),
+            }
+
+            connection = loop {
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+                let connecting = match self.endpoint.connect(address, &server_name) {
+                    Ok(connecting) => connecting,
+                    Err(e) => {
+                        warn!("failed to reconnect to orbit: {}", e);
+                        continue;
+                    }
+                };
+                match connecting.await {
+                    Ok(connection) => break connection,
+                    Err(e) => debug!("failed to reconnect to orbit: {}", e),
+                }
+            };
+            info!("reconnected to orbit at {}", address);
+        }
+    }
+
+    async fn run(
+        &self,
+        connection: &Connection,
+        outgoing: &mut mpsc::Receiver<Frame>,
+    ) -> Result<(), AnyhowError> {
+        let (mut send, recv) = connection.open_bi().await?;
+        let patterns: Vec<String> = self.patterns.lock().unwrap().iter().cloned().collect();
+        for pattern in patterns {
+            write_frame(&mut send, &Frame::Subscribe(pattern), self.codec).await?;
+        }
+
+        let broker = Arc::clone(&self.broker);
+        let mut reader = tokio::spawn(read_frames(recv, self.codec, move |frame| match frame {
+            Ok(Frame::Publish { subject, message }) => broker.publish(&subject, *message),
+            Ok(Frame::Subscribe(_)) => {}
+            Err(undecodable) => {
+                let subject = undecodable.subject.clone();
+                broker.deliver(&subject, Err(undecodable));
+            }
+        }));
+
+        let res = loop {
+            tokio::select! {
+                res = &mut reader => {
+                    break res
+                        .context("QUIC reader task failed")
+                        .and_then(|res| res)
+                        .and_then(|()| Err(anyhow::anyhow!("stream closed by orbit")));
+                }
+                frame = outgoing.recv() => match frame {
+                    Some(frame) => {
+                        if let Err(e) = write_frame(&mut send, &frame, self.codec).await {
+                            break Err(e);
+                        }
+                    }
+                    None => break Ok(()),
+                },
+            }
+        };
+        reader.abort();
+        res
+    }
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    Arc::new(transport)
+}
+
+async fn resolve(address: &str) -> Result<SocketAddr, AnyhowError> {
+    tokio::net::lookup_host(address)
+        .await
+        .with_context(|| format!("Failed to resolve {}", address))?
+        .next()
+        .with_context(|| format!("No address found for {}", address))
+}
+
+fn host(address: &str) -> &str {
+    address
+        .rsplit_once(':')
+        .map_or(address, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, AnyhowError> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read certificates from {}", path))
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, AnyhowError> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read private key from {}", path))?
+        .with_context(|| format!("No private key in {}", path))
+}
+
+// frames are a big endian u32 length followed by the encoded frame
+async fn write_frame(
+    send: &mut SendStream,
+    frame: &Frame,
+    codec: Codec,
+) -> Result<(), AnyhowError> {
+    let payload = codec.encode(frame)?;
+    send.write_all(&(payload.len() as u32).to_be_bytes())
+        .await?;
+    send.write_all(&payload).await?;
+    Ok(())
+}
+
+async fn write_frames(mut send: SendStream, mut rx: mpsc::Receiver<Frame>, codec: Codec) {
+    while let Some(frame) = rx.recv().await {
+        if let Err(e) = write_frame(&mut send, &frame, codec).await {
+            debug!("failed to write QUIC frame: {}", e);
+            break;
+        }
+    }
+}
+
+// publish frames whose envelope doesn't decode are handed on as
+// undecodable, like the other transports do, so they get dead-lettered
+async fn read_frames(
+    mut recv: RecvStream,
+    codec: Codec,
+    mut handle: impl FnMut(Result<Frame, Undecodable>),
+) -> Result<(), AnyhowError> {
+    loop {
+        let mut len = [0u8; 4];
+        match recv.read_exact(&mut len).await {
+            Ok(()) => {}
+            Err(ReadExactError::FinishedEarly(0)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        ensure!(
+            len <= MAX_FRAME_LEN,
+            "QUIC frame of {} bytes too large",
+            len
+        );
+
+        let mut payload = vec![0; len];
+        recv.read_exact(&mut payload).await?;
+        if let Some(frame) = decode_frame(codec, payload) {
+            handle(frame);
+        }
+    }
+}
+
+// None for frames that don't even name a subject, there is nobody to hand
+// them to
+fn decode_frame(codec: Codec, payload: Vec<u8>) -> Option<Result<Frame, Undecodable>> {
+    let error = match codec.decode(&payload) {
+        Ok(frame) => return Some(Ok(frame)),
+        Err(e) => e.to_string(),
+    };
+    match codec.decode(&payload) {
+        Ok(RawFrame::Publish { subject, .. }) => Some(Err(Undecodable {
+            subject,
+            payload,
+            error,
+        })),
+        _ => {
+            warn!("failed to deserialize QUIC frame: {}", error);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::{CodecConfig, WireFormat};
+    use crate::transport::pubsub::{Message, Sender};
+    use crate::transport::topics::{PubSubTopics, Target};
+
+    const CERT_FILE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/transport/testdata/quic-cert.pem"
+    );
+    const KEY_FILE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/transport/testdata/quic-key.pem"
+    );
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("localhost:4433"), "localhost");
+        assert_eq!(host("[::1]:4433"), "::1");
+        assert_eq!(host("orbit"), "orbit");
+    }
+
+    #[test]
+    fn test_decode_frame() {
+        let codec = Codec::default();
+        let frame = Frame::Publish {
+            subject: "gateway.local.gateway1.heartbeat".to_string(),
+            message: Box::new(Envelope::new(
+                "test",
+                Sender::gateway("test"),
+                0,
+                Message::Ping,
+            )),
+        };
+        assert!(matches!(
+            decode_frame(codec, codec.encode(&frame).unwrap()),
+            Some(Ok(Frame::Publish { .. }))
+        ));
+
+        // an envelope from a newer version still names its subject
+        let payload = codec
+            .encode(&serde_json::json!({
+                "Publish": {
+                    "subject": "gateway.local.gateway1.heartbeat",
+                    "message": { "version": 99 },
+                }
+            }))
+            .unwrap();
+        let Some(Err(undecodable)) = decode_frame(codec, payload.clone()) else {
+            panic!("expected an undecodable frame");
+        };
+        assert_eq!(undecodable.subject, "gateway.local.gateway1.heartbeat");
+        assert_eq!(undecodable.payload, payload);
+
+        assert!(decode_frame(codec, b"garbage".to_vec()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        // each side decodes whatever format the other encodes with
+        let msgpack = Codec::new(&CodecConfig {
+            format: WireFormat::MsgPack,
+            compress_above: Some(0),
+        });
+        let hub = QuicPubSub::new(
+            QuicConfig {
+                listen_address: Some("127.0.0.1:0".to_string()),
+                orbit_address: None,
+                server_name: None,
+                cert_file: Some(CERT_FILE.to_string()),
+                key_file: Some(KEY_FILE.to_string()),
+                ca_file: None,
+            },
+            msgpack,
+        )
+        .await
+        .unwrap();
+        let client_config = QuicConfig {
+            listen_address: None,
+            orbit_address: Some(format!("localhost:{}", hub.local_addr().unwrap().port())),
+            server_name: None,
+            cert_file: None,
+            key_file: None,
+            ca_file: Some(CERT_FILE.to_string()),
+        };
+        let gateway1 = QuicPubSub::new(client_config.clone(), Codec::default())
+            .await
+            .unwrap();
+        let gateway2 = QuicPubSub::new(client_config, msgpack).await.unwrap();
+
+        // gateway to orbit
+        let mut heartbeats = hub
+            .subscribe(PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::All))
+            .await
+            .unwrap();
+        gateway1
+            .publish(
+                PubSubTopics::PublishGatewayHeartbeat.gateway_subject("local", "gateway1"),
+                Envelope::new("test", Sender::gateway("test"), 0, Message::Ping),
+            )
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), heartbeats.recv()).await;
+        assert!(matches!(
+            received,
+            Ok(Some(Ok(Envelope {
+                message: Message::Ping,
+                ..
+            })))
+        ));
+
+        // orbit and gateways to every subscribed gateway, the subscription
+        // reaches orbit asynchronously so publish until it arrives
+        let [all, ..] =
+            &PubSubTopics::SubscribeGatewayHeartbeat.orbit_patterns("local", "gateway2")[..]
+        else {
+            unreachable!()
+        };
+        let mut statuses = gateway2.subscribe(all.clone()).await.unwrap();
+        for publisher in [&hub, &gateway1] {
+            let received = tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    publisher
+                        .publish(
+                            PubSubTopics::SubscribeGatewayHeartbeat
+                                .orbit_subject("orbit", Target::All),
+                            Envelope::new("test", Sender::gateway("test"), 0, Message::Pong),
+                        )
+                        .await
+                        .unwrap();
+                    let wait = tokio::time::sleep(Duration::from_millis(50));
+                    tokio::select! {
+                        msg = statuses.recv() => break msg,
+                        _ = wait => {}
+                    }
+                }
+            })
+            .await;
+            assert!(matches!(
+                received,
+                Ok(Some(Ok(Envelope {
+                    message: Message::Pong,
+                    ..
+                })))
+            ));
+        }
+    }
+}
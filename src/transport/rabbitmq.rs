@@ -0,0 +1,285 @@
+use crate::common::error::Error;
+use crate::common::types::RabbitMQConfig;
+use crate::transport::codec::{Codec, CONTENT_TYPE};
+use crate::transport::pubsub::{decode_delivery, Delivery, Envelope, PubSub};
+use anyhow::{Context, Error as AnyhowError};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
+    ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+};
+use lapin::types::FieldTable;
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::topics::Topic;
+
+#[derive(Clone, Debug)]
+pub struct RabbitMQPubSub {
+    conf: RabbitMQConfig,
+    connection: Arc<Connection>,
+    channel: Channel,
+    // tells apart the queues of the subscriptions of this process
+    subscriptions: Arc<AtomicUsize>,
+    codec: Codec,
+}
+
+impl RabbitMQPubSub {
+    pub async fn new(conf: RabbitMQConfig, codec: Codec) -> Result<Self, AnyhowError> {
+        let connect = Connection::connect(&conf.url, ConnectionProperties::default());
+        let connection = match conf.connection_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .context("Timed out connecting to RabbitMQ")?,
+            None => connect.await,
+        }
+        .context("Failed to connect to RabbitMQ")?;
+
+        let channel = connection
+            .create_channel()
+            .await
+            .context("Failed to create RabbitMQ channel")?;
+        channel
+            .exchange_declare(
+                &conf.exchange,
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .context("Failed to declare RabbitMQ exchange")?;
+
+        Ok(Self {
+            conf,
+            connection: Arc::new(connection),
+            channel,
+            subscriptions: Arc::new(AtomicUsize::new(0)),
+            codec,
+        })
+    }
+
+    // topics are dot separated like amqp routing keys and their `*` segments
+    // match a single word in bindings, so they map onto routing keys as is
+    fn routing_key(&self, topic: &Topic) -> String {
+        routing_key(&self.conf.routing_key, topic)
+    }
+
+    // every subscription gets an exclusive queue of its own so that every
+    // process sees every message, the queue goes away with the connection
+    fn queue_name(&self) -> String {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        format!(
+            "{}.{}.{}.{}",
+            self.conf.queue,
+            std::process::id(),
+            started,
+            self.subscriptions.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+}
+
+// the configured routing key namespaces the topics
+fn routing_key(prefix: &str, topic: &Topic) -> String {
+    if prefix.is_empty() {
+        topic.as_str().to_string()
+    } else {
+        format!("{}.{}", prefix, topic.as_str())
+    }
+}
+
+#[async_trait]
+impl PubSub for RabbitMQPubSub {
+    async fn publish(&self, topic: Topic, message: Envelope) -> Result<(), AnyhowError> {
+        let payload = self.codec.encode(&message)?;
+        self.channel
+            .basic_publish(
+                &self.conf.exchange,
+                &self.routing_key(&topic),
+                BasicPublishOptions::default(),
+                &payload,
+                BasicProperties::default().with_content_type(CONTENT_TYPE.into()),
+            )
+            .await
+            .map_err(|e| Error::PublishError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, AnyhowError> {
+        let channel = self
+            .connection
+            .create_channel()
+            .await
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        if let Some(prefetch_count) = self.conf.prefetch_count {
+            channel
+                .basic_qos(prefetch_count, BasicQosOptions::default())
+                .await
+                .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        }
+
+        let queue = self.queue_name();
+        let routing_key = self.routing_key(&topic);
+        channel
+            .queue_declare(
+                &queue,
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        channel
+            .queue_bind(
+                &queue,
+                &self.conf.exchange,
+                &routing_key,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        let mut consumer = channel
+            .basic_consume(
+                &queue,
+                &queue,
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        debug!("subscribed to {} with queue {}", routing_key, queue);
+
+        let (tx, rx) = mpsc::channel(100);
+        let codec = self.codec;
+        tokio::spawn(async move {
+            // the channel has to outlive the consumer
+            let _channel = channel;
+            while let Some(delivery) = consumer.next().await {
+                let delivery = match delivery {
+                    Ok(delivery) => delivery,
+                    Err(e) => {
+                        warn!("failed to receive from queue {}: {}", queue, e);
+                        break;
+                    }
+                };
+                let decoded = decode_delivery(codec, delivery.routing_key.as_str(), &delivery.data);
+                if tx.send(decoded).await.is_err() {
+                    break;
+                }
+                // acked once handed over so that prefetch_count bounds the
+                // messages in flight
+                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+                    warn!("failed to ack message on queue {}: {}", queue, e);
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::TransportConfig;
+    use crate::transport::pubsub::{Message, Sender};
+    use crate::transport::topics::{PubSubTopics, Target};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_config() {
+        let conf: TransportConfig = hcl::from_str(
+            r#"
+            type = "rabbitmq"
+            rabbitmq {
+              url                = "amqp://127.0.0.1:5672/%2f"
+              exchange           = "pluto"
+              queue              = "pluto"
+              routing_key        = "pluto"
+              connection_timeout = "5s"
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.rabbitmq.unwrap().connection_timeout,
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_routing_key() {
+        assert_eq!(
+            routing_key(
+                "",
+                &PubSubTopics::GatewayToOrbitStats.gateway_pattern(Target::All)
+            ),
+            "gateway.*.*.stats"
+        );
+        assert_eq!(
+            routing_key(
+                "pluto",
+                &PubSubTopics::OrbitToGatewayStats.orbit_subject("orbit", Target::All)
+            ),
+            "pluto.orbit.orbit.all.stats"
+        );
+    }
+
+    // needs a local broker, run it with `cargo test -- --ignored` and point
+    // AMQP_URL at it when it isn't on the default port
+    #[tokio::test]
+    #[ignore]
+    async fn test_every_subscriber_gets_every_message() {
+        let conf = RabbitMQConfig {
+            url: std::env::var("AMQP_URL")
+                .unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".to_string()),
+            exchange: "pluto-test".to_string(),
+            queue: "pluto-test".to_string(),
+            routing_key: "pluto".to_string(),
+            prefetch_count: Some(10),
+            connection_timeout: None,
+        };
+        let publisher = RabbitMQPubSub::new(conf.clone(), Codec::default())
+            .await
+            .unwrap();
+        let subscriber = RabbitMQPubSub::new(conf, Codec::default()).await.unwrap();
+
+        let topic = PubSubTopics::PublishConfigUpdate.orbit_subject("orbit", Target::All);
+        let mut rx1 = subscriber.subscribe(topic.clone()).await.unwrap();
+        let mut rx2 = subscriber.subscribe(topic.clone()).await.unwrap();
+        publisher
+            .publish(
+                topic,
+                Envelope::new("test", Sender::gateway("test"), 0, Message::Ping),
+            )
+            .await
+            .unwrap();
+
+        for rx in [&mut rx1, &mut rx2] {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap();
+            assert!(matches!(
+                message,
+                Some(Ok(Envelope {
+                    message: Message::Ping,
+                    ..
+                }))
+            ));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::common::error::Error;
+use crate::common::types::RedisConfig;
+use crate::transport::codec::Codec;
+use crate::transport::pubsub::{decode_delivery, Delivery, Envelope, PubSub};
+use anyhow::{Context, Error as AnyhowError};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::topics::Topic;
+
+#[derive(Clone)]
+pub struct RedisPubSub {
+    client: redis::Client,
+    publisher: ConnectionManager,
+    codec: Codec,
+}
+
+impl std::fmt::Debug for RedisPubSub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisPubSub")
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+impl RedisPubSub {
+    pub async fn new(conf: RedisConfig, codec: Codec) -> Result<Self, AnyhowError> {
+        let client = redis::Client::open(conf.url.as_str()).context("Invalid redis url")?;
+        let publisher = ConnectionManager::new(client.clone())
+            .await
+            .context("Failed to connect to redis")?;
+        Ok(Self {
+            client,
+            publisher,
+            codec,
+        })
+    }
+}
+
+#[async_trait]
+impl PubSub for RedisPubSub {
+    async fn publish(&self, topic: Topic, message: Envelope) -> Result<(), AnyhowError> {
+        let payload = self.codec.encode(&message)?;
+        self.publisher
+            .clone()
+            .publish::<_, _, ()>(topic.as_str(), payload)
+            .await
+            .map_err(|e| Error::PublishError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<mpsc::Receiver<Delivery>, AnyhowError> {
+        // a connection in subscriber mode can't run other commands, so every
+        // subscription gets a connection of its own
+        let mut pubsub = self
+            .client
+            .get_async_pubsub()
+            .await
+            .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        // redis glob patterns match `*` against any run of characters, which
+        // is fine as the ids in subjects never contain dots
+        if topic.is_pattern() {
+            pubsub.psubscribe(topic.as_str()).await
+        } else {
+            pubsub.subscribe(topic.as_str()).await
+        }
+        .map_err(|e| Error::SubscriptionError(e.to_string()))?;
+        debug!("subscribed to redis channel: {}", topic.as_str());
+
+        let (tx, rx) = mpsc::channel(100);
+        let codec = self.codec;
+        tokio::spawn(async move {
+            let mut messages = pubsub.into_on_message();
+            while let Some(msg) = messages.next().await {
+                let delivery =
+                    decode_delivery(codec, msg.get_channel_name(), msg.get_payload_bytes());
+                if tx.send(delivery).await.is_err() {
+                    break;
+                }
+            }
+            warn!("redis subscription to {} closed", topic.as_str());
+        });
+
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pubsub::{Message, Sender};
+    use crate::transport::topics::{PubSubTopics, Target};
+
+    // needs a local redis-server, run it with `cargo test -- --ignored` and
+    // point REDIS_URL at it when it isn't on the default port
+    #[tokio::test]
+    #[ignore]
+    async fn test_pattern_subscription() {
+        let conf = RedisConfig {
+            url: std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string()),
+        };
+        let pubsub = RedisPubSub::new(conf, Codec::default()).await.unwrap();
+        let mut rx = pubsub
+            .subscribe(PubSubTopics::GatewayToOrbitStats.gateway_pattern(Target::All))
+            .await
+            .unwrap();
+
+        // a concrete channel matching the pattern
+        pubsub
+            .publish(
+                PubSubTopics::GatewayToOrbitStats.gateway_subject("local", "gateway1"),
+                Envelope::new("test", Sender::gateway("test"), 0, Message::Ping),
+            )
+            .await
+            .unwrap();
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            message,
+            Some(Ok(Envelope {
+                message: Message::Ping,
+                ..
+            }))
+        ));
+    }
+}
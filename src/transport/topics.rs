@@ -1,7 +1,24 @@
-#[derive(Debug, Clone)]
+use std::fmt;
+
+use super::pubsub::{Role, Sender};
+
+// The kinds of messages exchanged between orbit and the gateways, the
+// builders below turn them into the subjects messages are published on and
+// the patterns subscribers match them with:
+//
+//   gateway.{region}.{gateway_id}.{kind}            published by a gateway
+//   orbit.{orbit_id}.all.{kind}                     orbit to every gateway
+//   orbit.{orbit_id}.region.{region}.{kind}         orbit to a region
+//   orbit.{orbit_id}.gateway.{gateway_id}.{kind}    orbit to a single gateway
+//   rpc.orbit                                       requests any orbit answers
+//   rpc.gateway.{gateway_id}                        requests to a gateway
+//   reply.{role}.{id}                               replies to a requester
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PubSubTopics {
     GatewayToOrbitStats,       // from gateway to orbit
     OrbitToGatewayStats,       // from orbit to gateway
+    GatewayToOrbitMeshStats,   // from gateway to orbit
+    OrbitToGatewayMeshStats,   // from orbit to gateway
     PublishGatewayHeartbeat,   // from gateway to orbit
     SubscribeGatewayHeartbeat, // from orbit to gateway
     PublishGatewayFailover,    // from gateway to orbit
@@ -12,19 +29,260 @@ pub enum PubSubTopics {
     SubscribeGatewayMetrics,   // from orbit to gateway
 }
 
+// the gateways an orbit message is addressed to, or a subscriber listens to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target<'a> {
+    All,
+    Region(&'a str),
+    Gateway(&'a str),
+}
+
+// a subject to publish on, or a pattern to subscribe with when one of its
+// tokens is `*` or `>`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Topic(String);
+
+impl Topic {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_pattern(&self) -> bool {
+        self.0.split('.').any(|token| token == "*" || token == ">")
+    }
+
+    // where `sender` republishes messages it could not decode
+    pub fn dead_letter(sender: &Sender) -> Topic {
+        Topic(format!(
+            "deadletter.{}.{}",
+            role(sender.role),
+            token(&sender.id)
+        ))
+    }
+
+    // where requests to orbit go, whichever orbit is up answers them
+    pub fn orbit_requests() -> Topic {
+        Topic("rpc.orbit".to_string())
+    }
+
+    // where requests to the gateway go
+    pub fn gateway_requests(gateway_id: &str) -> Topic {
+        Topic(format!("rpc.gateway.{}", token(gateway_id)))
+    }
+
+    // where replies to the requests of `sender` go, unless the backend
+    // routes replies itself
+    pub fn replies(sender: &Sender) -> Topic {
+        Topic(format!("reply.{}.{}", role(sender.role), token(&sender.id)))
+    }
+
+    // a subject as received, e.g. the one a reply is expected on
+    pub(crate) fn from_subject(subject: &str) -> Topic {
+        Topic(subject.to_string())
+    }
+
+    // the same subject namespaced under `cluster`
+    pub fn in_cluster(&self, cluster: &str) -> Topic {
+        Topic(format!("{}.{}", token(cluster), self.0))
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 impl PubSubTopics {
-    pub fn as_str(&self) -> &'static str {
+    fn kind(&self) -> &'static str {
         match self {
-            PubSubTopics::GatewayToOrbitStats => "orbit.latency.stats", // from gateway to orbit
-            PubSubTopics::OrbitToGatewayStats => "orbit.*.latency.stats", // from orbit to gateway
-            PubSubTopics::PublishGatewayHeartbeat => "orbit.gateway.heartbeat", // from gateway to orbit
-            PubSubTopics::SubscribeGatewayHeartbeat => "orbit.*.heartbeat", // from orbit to gateway
-            PubSubTopics::PublishGatewayFailover => "orbit.gateway.failover", // from gateway to orbit
-            PubSubTopics::SubscribeGatewayFailover => "orbit.*.failover", // from orbit to gateway
-            PubSubTopics::PublishConfigUpdate => "orbit.config.update",   // from orbit to gateways
-            PubSubTopics::SubscribeConfigUpdate => "orbit.*.config.update", // from gateways to orbit
-            PubSubTopics::PublishGatewayMetrics => "orbit.gateway.metrics", // from gateway to orbit
-            PubSubTopics::SubscribeGatewayMetrics => "orbit.*.metrics",     // from orbit to gateway
+            PubSubTopics::GatewayToOrbitStats | PubSubTopics::OrbitToGatewayStats => "stats",
+            PubSubTopics::GatewayToOrbitMeshStats | PubSubTopics::OrbitToGatewayMeshStats => "mesh",
+            PubSubTopics::PublishGatewayHeartbeat => "heartbeat",
+            PubSubTopics::SubscribeGatewayHeartbeat => "status",
+            PubSubTopics::PublishGatewayFailover | PubSubTopics::SubscribeGatewayFailover => {
+                "failover"
+            }
+            PubSubTopics::PublishConfigUpdate | PubSubTopics::SubscribeConfigUpdate => "config",
+            PubSubTopics::PublishGatewayMetrics | PubSubTopics::SubscribeGatewayMetrics => {
+                "metrics"
+            }
         }
     }
+
+    // patterns matching every subject that carries the latest state of a
+    // gateway, whoever publishes it, as opposed to events, requests and
+    // replies where every message counts
+    pub fn state_patterns() -> Vec<Topic> {
+        let published_by_gateways = [
+            PubSubTopics::GatewayToOrbitStats,
+            PubSubTopics::GatewayToOrbitMeshStats,
+            PubSubTopics::PublishGatewayHeartbeat,
+        ]
+        .map(|topic| topic.gateway_pattern(Target::All));
+        let published_by_orbit = [
+            PubSubTopics::OrbitToGatewayStats,
+            PubSubTopics::OrbitToGatewayMeshStats,
+            PubSubTopics::SubscribeGatewayHeartbeat,
+        ]
+        .into_iter()
+        .flat_map(|topic| {
+            ["all", "region.*", "gateway.*"]
+                .map(|target| Topic(format!("orbit.*.{}.{}", target, topic.kind())))
+        });
+        published_by_gateways
+            .into_iter()
+            .chain(published_by_orbit)
+            .collect()
+    }
+
+    // subject a gateway publishes on
+    pub fn gateway_subject(self, region: &str, gateway_id: &str) -> Topic {
+        Topic(format!(
+            "gateway.{}.{}.{}",
+            token(region),
+            token(gateway_id),
+            self.kind()
+        ))
+    }
+
+    // pattern matching what the targeted gateways publish
+    pub fn gateway_pattern(self, target: Target) -> Topic {
+        let (region, gateway_id) = match target {
+            Target::All => ("*".to_string(), "*".to_string()),
+            Target::Region(region) => (token(region), "*".to_string()),
+            Target::Gateway(gateway_id) => ("*".to_string(), token(gateway_id)),
+        };
+        Topic(format!("gateway.{}.{}.{}", region, gateway_id, self.kind()))
+    }
+
+    // subject orbit publishes on to reach the targeted gateways
+    pub fn orbit_subject(self, orbit_id: &str, target: Target) -> Topic {
+        Topic(format!(
+            "orbit.{}.{}.{}",
+            token(orbit_id),
+            target_tokens(target),
+            self.kind()
+        ))
+    }
+
+    // patterns a gateway subscribes with to get everything orbit addresses
+    // to it, whether sent to all gateways, its region or to it alone
+    pub fn orbit_patterns(self, region: &str, gateway_id: &str) -> Vec<Topic> {
+        [
+            Target::All,
+            Target::Region(region),
+            Target::Gateway(gateway_id),
+        ]
+        .into_iter()
+        .map(|target| Topic(format!("orbit.*.{}.{}", target_tokens(target), self.kind())))
+        .collect()
+    }
+}
+
+fn role(role: Role) -> &'static str {
+    match role {
+        Role::Orbit => "orbit",
+        Role::Gateway => "gateway",
+    }
+}
+
+fn target_tokens(target: Target) -> String {
+    match target {
+        Target::All => "all".to_string(),
+        Target::Region(region) => format!("region.{}", token(region)),
+        Target::Gateway(gateway_id) => format!("gateway.{}", token(gateway_id)),
+    }
+}
+
+// ids end up as single subject tokens, anything that would split them or
+// act as a wildcard is replaced. This also keeps subjects valid kafka topic
+// names
+pub(crate) fn token(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory::matches;
+
+    #[test]
+    fn test_gateway_subjects() {
+        let subject = PubSubTopics::PublishGatewayHeartbeat.gateway_subject("ap-south-1", "mumbai");
+        assert_eq!(subject.as_str(), "gateway.ap-south-1.mumbai.heartbeat");
+        assert!(!subject.is_pattern());
+
+        let all = PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::All);
+        let region = PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::Region("eu"));
+        let gateway =
+            PubSubTopics::PublishGatewayHeartbeat.gateway_pattern(Target::Gateway("mumbai"));
+        assert!(all.is_pattern());
+        assert!(matches(all.as_str(), subject.as_str()));
+        assert!(!matches(region.as_str(), subject.as_str()));
+        assert!(matches(gateway.as_str(), subject.as_str()));
+
+        let stats = PubSubTopics::GatewayToOrbitStats.gateway_pattern(Target::All);
+        assert!(!matches(stats.as_str(), subject.as_str()));
+    }
+
+    #[test]
+    fn test_orbit_subjects() {
+        let patterns = PubSubTopics::PublishConfigUpdate.orbit_patterns("eu", "paris");
+        let reaches = |target| {
+            let subject = PubSubTopics::PublishConfigUpdate.orbit_subject("orbit", target);
+            assert!(!subject.is_pattern());
+            patterns
+                .iter()
+                .any(|pattern| matches(pattern.as_str(), subject.as_str()))
+        };
+        assert!(reaches(Target::All));
+        assert!(reaches(Target::Region("eu")));
+        assert!(reaches(Target::Gateway("paris")));
+        assert!(!reaches(Target::Region("us")));
+        assert!(!reaches(Target::Gateway("berlin")));
+    }
+
+    #[test]
+    fn test_ids_are_single_tokens() {
+        assert_eq!(
+            PubSubTopics::GatewayToOrbitStats
+                .gateway_subject("eu.west", "*")
+                .as_str(),
+            "gateway.eu_west._.stats"
+        );
+    }
+
+    #[test]
+    fn test_state_patterns() {
+        let patterns = PubSubTopics::state_patterns();
+        let is_state = |subject: Topic| {
+            patterns
+                .iter()
+                .any(|pattern| matches(pattern.as_str(), subject.as_str()))
+        };
+        assert!(is_state(
+            PubSubTopics::PublishGatewayHeartbeat.gateway_subject("eu", "paris")
+        ));
+        assert!(is_state(
+            PubSubTopics::OrbitToGatewayMeshStats.orbit_subject("orbit", Target::All)
+        ));
+        assert!(is_state(
+            PubSubTopics::SubscribeGatewayHeartbeat.orbit_subject("orbit", Target::Region("eu"))
+        ));
+        assert!(!is_state(
+            PubSubTopics::PublishGatewayFailover.gateway_subject("eu", "paris")
+        ));
+        assert!(!is_state(Topic::orbit_requests()));
+        assert!(!is_state(Topic::replies(&Sender::gateway("paris"))));
+        assert!(!is_state(Topic::dead_letter(&Sender::orbit("orbit"))));
+    }
 }